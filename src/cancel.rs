@@ -0,0 +1,37 @@
+use std::{
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::Duration,
+};
+
+// Longest a wait or sleep goes without checking whether it should stop,
+// for everything blocking on an abort flag
+pub const ABORT_POLL: Duration = Duration::from_millis(50);
+
+/// Stops a running download from another thread. Clones share the same
+/// state, so one can be handed to the downloader and another kept around to
+/// cancel it.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Ask the download to stop, run() then cleans up and returns
+    /// [`DownloadError::Cancelled`](crate::DownloadError::Cancelled)
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    // For readers that poll a plain flag
+    pub(crate) fn flag(&self) -> &AtomicBool {
+        &self.flag
+    }
+}
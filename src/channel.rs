@@ -1,32 +1,83 @@
-use std::sync::{mpsc::{channel, Receiver, Sender}, Arc, Mutex};
+use std::{
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, SendError, Sender, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 use log::error;
 
-#[derive(Clone)]
+enum ChannelSender<T> {
+    Unbounded(Sender<T>),
+    // Blocks the sender once the channel holds `capacity` messages
+    Bounded(SyncSender<T>),
+}
+
+impl<T> ChannelSender<T> {
+    fn send(&self, data: T) -> Result<(), SendError<T>> {
+        match self {
+            ChannelSender::Unbounded(tx) => tx.send(data),
+            ChannelSender::Bounded(tx) => tx.send(data),
+        }
+    }
+
+    fn try_send(&self, data: T) -> Result<(), TrySendError<T>> {
+        match self {
+            ChannelSender::Unbounded(tx) => {
+                tx.send(data).map_err(|err| TrySendError::Disconnected(err.0))
+            }
+            ChannelSender::Bounded(tx) => tx.try_send(data),
+        }
+    }
+}
+
 pub struct SharedChannel<T> {
     name: String,
-    tx: Arc<Mutex<Sender<T>>>,
+    // None once closed
+    tx: Arc<Mutex<Option<ChannelSender<T>>>>,
     rx: Arc<Mutex<Receiver<T>>>,
     lock_try_max: u8,
 }
 
-impl<T: Clone> SharedChannel<T> {
+// Clones share the channel, so T itself doesn't have to be Clone
+impl<T> Clone for SharedChannel<T> {
+    fn clone(&self) -> Self {
+        SharedChannel{
+            name: self.name.clone(),
+            tx: self.tx.clone(),
+            rx: self.rx.clone(),
+            lock_try_max: self.lock_try_max,
+        }
+    }
+}
+
+impl<T> SharedChannel<T> {
     pub fn new(name: &str) -> Self {
         let (tx, rx) = channel::<T>();
-        let shared_tx = Arc::new(Mutex::new(tx));
+        Self::from_parts(name, ChannelSender::Unbounded(tx), rx)
+    }
+
+    pub fn bounded(name: &str, capacity: usize) -> Self {
+        let (tx, rx) = sync_channel::<T>(capacity);
+        Self::from_parts(name, ChannelSender::Bounded(tx), rx)
+    }
+
+    fn from_parts(name: &str, tx: ChannelSender<T>, rx: Receiver<T>) -> Self {
+        let shared_tx = Arc::new(Mutex::new(Some(tx)));
         let shared_rx = Arc::new(Mutex::new(rx));
-        return SharedChannel{
+        SharedChannel{
             name: name.to_string(),
             tx: shared_tx,
             rx: shared_rx,
             lock_try_max: 100,
-        };
+        }
     }
 
     pub fn send(&self, data: T) -> Option<()> {
         for _i in 0..self.lock_try_max {
             match self.tx.lock() {
                 Ok(locked_tx) => {
-                    if let Ok(result) = locked_tx.send(data.clone()) {
+                    if let Some(Ok(result)) = locked_tx.as_ref().map(|tx| tx.send(data)) {
                         return Some(result);
                     } else {
                         break;
@@ -37,7 +88,37 @@ impl<T: Clone> SharedChannel<T> {
                 }
             };
         }
-        return None;
+        None
+    }
+
+    pub fn try_send(&self, data: T) -> Result<(), TrySendError<T>> {
+        for _i in 0..self.lock_try_max {
+            match self.tx.lock() {
+                Ok(locked_tx) => {
+                    match locked_tx.as_ref() {
+                        Some(tx) => return tx.try_send(data),
+                        None => return Err(TrySendError::Disconnected(data)),
+                    }
+                }
+                Err(err) => {
+                    error!("error locking shared channel {} tx: {}", self.name, err);
+                }
+            };
+        }
+        Err(TrySendError::Disconnected(data))
+    }
+
+    // Drop the sending side for every clone of the channel. Messages already
+    // sent can still be received, after that recv() returns None.
+    pub fn close(&self) {
+        match self.tx.lock() {
+            Ok(mut locked_tx) => {
+                locked_tx.take();
+            }
+            Err(err) => {
+                error!("error locking shared channel {} tx: {}", self.name, err);
+            }
+        }
     }
 
     pub fn recv(&self) -> Option<T> {
@@ -55,24 +136,121 @@ impl<T: Clone> SharedChannel<T> {
                 }
             };
         }
-        return None;
+        None
     }
 
+    // A message if one is waiting. Gives up right away while another
+    // receiver holds the lock, it only does so while waiting for messages.
     pub fn try_recv(&self) -> Option<T> {
+        match self.rx.try_lock() {
+            Ok(locked_rx) => locked_rx.try_recv().ok(),
+            Err(_) => None,
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
         for _i in 0..self.lock_try_max {
             match self.rx.lock() {
                 Ok(locked_rx) => {
-                    if let Ok(result) = locked_rx.try_recv() {
-                        return Some(result);
-                    } else {
-                        break;
-                    }
+                    return locked_rx.recv_timeout(timeout);
                 }
                 Err(err) => {
                     error!("error locking shared channel {} rx: {}", self.name, err);
                 }
             };
         }
-        return None;
+        Err(RecvTimeoutError::Disconnected)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Instant};
+    use super::*;
+
+    #[test]
+    fn recv_timeout_waits_for_a_message() {
+        let chan = SharedChannel::new("test");
+        let started = Instant::now();
+        assert_eq!(chan.recv_timeout(Duration::from_millis(50)), Err(RecvTimeoutError::Timeout));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let sender = chan.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            sender.send(7)
+        });
+        // Wakes up with the message instead of running into the timeout
+        let started = Instant::now();
+        assert_eq!(chan.recv_timeout(Duration::from_secs(10)), Ok(7));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        handle.join().unwrap().unwrap();
+
+        chan.send(8).unwrap();
+        chan.close();
+        assert_eq!(chan.recv_timeout(Duration::from_millis(10)), Ok(8));
+        assert_eq!(chan.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn bounded_channels_block_when_full() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let chan = SharedChannel::bounded("test", 2);
+        chan.try_send(1).unwrap();
+        chan.try_send(2).unwrap();
+        assert!(matches!(chan.try_send(3), Err(TrySendError::Full(3))));
+
+        let sent = Arc::new(AtomicBool::new(false));
+        let (sender, sender_sent) = (chan.clone(), sent.clone());
+        let handle = thread::spawn(move || {
+            sender.send(3).unwrap();
+            sender_sent.store(true, Ordering::SeqCst);
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sent.load(Ordering::SeqCst));
+        // Room for one more lets the sender go on
+        assert_eq!(chan.recv(), Some(1));
+        handle.join().unwrap();
+        assert!(sent.load(Ordering::SeqCst));
+        assert_eq!((chan.recv(), chan.recv()), (Some(2), Some(3)));
+    }
+
+    #[test]
+    fn messages_move_through_without_clone() {
+        // Neither Clone nor Copy
+        #[derive(Debug, PartialEq)]
+        struct Payload(Vec<u8>);
+        let chan = SharedChannel::new("test");
+        chan.send(Payload(vec![1, 2, 3])).unwrap();
+        chan.clone().send(Payload(vec![4])).unwrap();
+        assert_eq!(chan.recv(), Some(Payload(vec![1, 2, 3])));
+        assert_eq!(chan.try_recv(), Some(Payload(vec![4])));
+        assert_eq!(chan.try_recv(), None);
+    }
+
+    #[test]
+    fn receivers_stop_once_closed() {
+        let chan = SharedChannel::new("test");
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let chan = chan.clone();
+                // Like a worker, runs until the channel is closed and drained
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    while let Some(task) = chan.recv() {
+                        received.push(task);
+                    }
+                    received
+                })
+            })
+            .collect();
+        for task in 0..30 {
+            chan.send(task).unwrap();
+        }
+        chan.close();
+        let mut received: Vec<i32> = workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect();
+        received.sort();
+        assert_eq!(received, (0..30).collect::<Vec<_>>());
+        assert_eq!(chan.send(30), None);
+        assert!(matches!(chan.try_send(30), Err(TrySendError::Disconnected(30))));
+    }
+}
@@ -0,0 +1,253 @@
+use std::{fmt, io, time::Duration};
+use crate::transport::StatusError;
+
+// Failed chunks named in the message, the rest are only counted
+const MAX_LISTED: usize = 5;
+
+/// A chunk that still failed after all its attempts, with its bytes in the
+/// output file
+#[derive(Debug, Clone)]
+pub struct FailedChunk {
+    pub id: usize,
+    pub start: usize,
+    pub end: usize,
+    pub attempts: usize,
+    /// What went wrong with its last failed attempt, e.g. a refused
+    /// connection
+    pub error: Option<String>,
+}
+
+/// Why a download failed, returned by [`Downloader`](crate::Downloader)
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The server doesn't serve byte ranges and the download needs them
+    RangeUnsupported,
+    /// The downloaded file doesn't have the expected sha256 digest
+    ChecksumMismatch { expected: String, actual: String },
+    /// The downloaded file or stream doesn't have the announced size
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The server refused to serve these bytes of the file, whose length
+    /// it reports as `content_length`
+    RangeNotSatisfiable { start: usize, end: usize, content_length: Option<usize> },
+    /// The server answered a chunk request with an HTTP status that isn't
+    /// retried, see
+    /// [`DownloaderBuilder::retry_on_status`](crate::DownloaderBuilder::retry_on_status)
+    UnretriedStatus { chunk_id: usize, start: usize, end: usize, status: u16 },
+    /// Chunks still failed after all their attempts. The chunks in flight
+    /// when the first one failed were given the chance to finish, so this
+    /// lists every chunk that failed.
+    TooManyRetries { chunks: Vec<FailedChunk> },
+    /// Stopped through the cancel flag
+    Cancelled,
+    /// Not finished within the time set with
+    /// [`max_total_time`](crate::DownloaderBuilder::max_total_time)
+    DeadlineExceeded { limit: Duration },
+    /// Reading or writing local files failed, `context` says what was done
+    Io { context: String, source: io::Error },
+    /// A request failed, `context` says which one
+    Http { context: String, source: Box<ureq::Error> },
+    /// The server answered with an error status, see [`StatusError`]
+    Status(StatusError),
+    /// Anything else, like invalid options or a misbehaving server
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::RangeUnsupported => {
+                write!(f, "server does not support byte ranges")
+            }
+            DownloadError::ChecksumMismatch { expected, actual } => {
+                write!(f, "sha256 mismatch: expected {}, got {}", expected, actual)
+            }
+            DownloadError::SizeMismatch { expected, actual } => {
+                write!(f, "download size mismatch: expected {} bytes, got {}", expected, actual)
+            }
+            DownloadError::RangeNotSatisfiable { start, end, content_length: Some(length) } => {
+                write!(f, "server refused bytes {}-{} of a file it reports as {} bytes", start, end, length)
+            }
+            DownloadError::RangeNotSatisfiable { start, end, content_length: None } => {
+                write!(f, "server refused bytes {}-{} and no longer reports the file length", start, end)
+            }
+            DownloadError::UnretriedStatus { chunk_id, start, end, status } => {
+                write!(f, "chunk id={} (bytes {}-{}) failed with status {}, which isn't retried", chunk_id, start, end, status)
+            }
+            DownloadError::TooManyRetries { chunks } => {
+                if let [chunk] = &chunks[..] {
+                    write!(f, "chunk id={} (bytes {}-{}) failed after {} attempts", chunk.id, chunk.start, chunk.end, chunk.attempts)?;
+                } else {
+                    write!(f, "{} chunks failed after all their attempts:", chunks.len())?;
+                    for (i, chunk) in chunks.iter().take(MAX_LISTED).enumerate() {
+                        let separator = if i == 0 { " " } else { ", " };
+                        write!(f, "{}id={} (bytes {}-{})", separator, chunk.id, chunk.start, chunk.end)?;
+                    }
+                    if chunks.len() > MAX_LISTED {
+                        write!(f, " and {} more", chunks.len() - MAX_LISTED)?;
+                    }
+                }
+                // The cause of the first failure, the others are often the same
+                if let Some(error) = chunks.iter().find_map(|chunk| chunk.error.as_deref()) {
+                    write!(f, ": {}", error)?;
+                }
+                Ok(())
+            }
+            DownloadError::Cancelled => write!(f, "download cancelled"),
+            DownloadError::DeadlineExceeded { limit } => {
+                write!(f, "download did not finish within {:?}", limit)
+            }
+            DownloadError::Io { context, source } => write_context(f, context, "I/O error", source),
+            DownloadError::Http { context, source } => write_context(f, context, "request failed", source.as_ref()),
+            DownloadError::Status(err) => write!(f, "{}", err),
+            DownloadError::Other(err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownloadError::Io { source, .. } => Some(source),
+            DownloadError::Http { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// The source is reported separately, see source(), unless the whole chain
+// is asked for with {:#}
+fn write_context(
+    f: &mut fmt::Formatter<'_>,
+    context: &str,
+    fallback: &str,
+    source: &(dyn std::error::Error + 'static),
+) -> fmt::Result {
+    if !f.alternate() {
+        return write!(f, "{}", if context.is_empty() { fallback } else { context });
+    }
+    let mut causes = Vec::new();
+    if !context.is_empty() {
+        causes.push(context.to_string());
+    }
+    causes.extend(std::iter::successors(Some(source), |err| err.source()).map(|err| err.to_string()));
+    write!(f, "{}", causes.join(": "))
+}
+
+fn join_context(outer: &str, inner: &str) -> String {
+    match (outer.is_empty(), inner.is_empty()) {
+        (true, _) => inner.to_string(),
+        (_, true) => outer.to_string(),
+        _ => format!("{}: {}", outer, inner),
+    }
+}
+
+// Contexts attached on top of the first error of type `E` in the chain
+fn context_of<E: std::error::Error + 'static>(err: &anyhow::Error) -> Option<String> {
+    let causes: Vec<&(dyn std::error::Error + 'static)> = err.chain().collect();
+    let position = causes.iter().position(|cause| cause.is::<E>())?;
+    let context: Vec<String> = causes[..position].iter().map(|cause| cause.to_string()).collect();
+    Some(context.join(": "))
+}
+
+impl From<StatusError> for DownloadError {
+    fn from(err: StatusError) -> Self {
+        DownloadError::Status(err)
+    }
+}
+
+// Internally errors are anyhow errors with context attached on the way up,
+// they are sorted into variants once they leave the crate
+impl From<anyhow::Error> for DownloadError {
+    fn from(err: anyhow::Error) -> Self {
+        let http_context = context_of::<ureq::Error>(&err).unwrap_or_default();
+        let io_context = context_of::<io::Error>(&err).unwrap_or_default();
+        // A DownloadError of a transport, with more context attached since
+        let outer_context = context_of::<DownloadError>(&err).unwrap_or_default();
+        let err = match err.downcast::<DownloadError>() {
+            Ok(DownloadError::Io { context, source }) => {
+                return DownloadError::Io { context: join_context(&outer_context, &context), source };
+            }
+            Ok(DownloadError::Http { context, source }) => {
+                return DownloadError::Http { context: join_context(&outer_context, &context), source };
+            }
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<StatusError>() {
+            Ok(err) => return DownloadError::Status(err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<ureq::Error>() {
+            Ok(source) => return DownloadError::Http { context: http_context, source: Box::new(source) },
+            Err(err) => err,
+        };
+        match err.downcast::<io::Error>() {
+            Ok(source) => DownloadError::Io { context: io_context, source },
+            Err(err) => DownloadError::Other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+    use super::*;
+
+    fn status_error(status: u16) -> StatusError {
+        StatusError{url: "http://example.com/file".to_string(), status, retry_after: None}
+    }
+
+    #[test]
+    fn sorts_anyhow_errors_into_variants() {
+        let err = DownloadError::from(anyhow::Error::new(DownloadError::Cancelled).context("stopping"));
+        assert!(matches!(err, DownloadError::Cancelled));
+        let err = DownloadError::from(anyhow::Error::new(status_error(503)).context("GET request failed"));
+        assert!(matches!(err, DownloadError::Status(StatusError{status: 503, ..})));
+        let io_err = io::Error::new(io::ErrorKind::NotFound, "gone");
+        let err = DownloadError::from(anyhow::Error::new(io_err).context("failed to open file"));
+        assert!(matches!(&err, DownloadError::Io{context, source} if context == "failed to open file" && source.kind() == io::ErrorKind::NotFound));
+        let err = DownloadError::from(anyhow!("invalid content length"));
+        assert!(matches!(&err, DownloadError::Other(_)));
+        assert_eq!(err.to_string(), "invalid content length");
+    }
+
+    #[test]
+    fn keeps_context_of_transport_errors() {
+        let io_err = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let transport_err = DownloadError::Io{context: "FTP request failed".to_string(), source: io_err};
+        let err = DownloadError::from(Err::<(), _>(transport_err).context("failed to probe mirror").unwrap_err());
+        assert!(matches!(&err, DownloadError::Io{context, ..} if context == "failed to probe mirror: FTP request failed"));
+    }
+
+    #[test]
+    fn status_errors_convert() {
+        let err = DownloadError::from(status_error(404));
+        assert!(matches!(err, DownloadError::Status(StatusError{status: 404, ..})));
+        assert_eq!(err.to_string(), "http://example.com/file: status code 404");
+    }
+
+    #[test]
+    fn failed_chunks_are_listed() {
+        let chunk = |id: usize| FailedChunk{id, start: id * 10, end: id * 10 + 9, attempts: 3, error: None};
+        let err = DownloadError::TooManyRetries{chunks: vec![FailedChunk{error: Some("reset".to_string()), ..chunk(4)}]};
+        assert_eq!(err.to_string(), "chunk id=4 (bytes 40-49) failed after 3 attempts: reset");
+        let err = DownloadError::TooManyRetries{chunks: (0..7).map(chunk).collect()};
+        assert_eq!(
+            err.to_string(),
+            "7 chunks failed after all their attempts: id=0 (bytes 0-9), id=1 (bytes 10-19), \
+             id=2 (bytes 20-29), id=3 (bytes 30-39), id=4 (bytes 40-49) and 2 more",
+        );
+    }
+
+    #[test]
+    fn the_alternate_form_includes_the_source() {
+        let err = DownloadError::Io {
+            context: "failed to write chunk".to_string(),
+            source: io::Error::other("disk full"),
+        };
+        assert_eq!(err.to_string(), "failed to write chunk");
+        assert_eq!(format!("{:#}", err), "failed to write chunk: disk full");
+        let err = DownloadError::Io { context: String::new(), source: io::Error::other("disk full") };
+        assert_eq!((err.to_string(), format!("{:#}", err)), ("I/O error".to_string(), "disk full".to_string()));
+    }
+}
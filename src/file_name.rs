@@ -0,0 +1,130 @@
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+// Used when neither the server nor the url suggest a name
+const DEFAULT_FILE_NAME: &str = "index.html";
+
+// Pick an output file name for `url`: the name suggested by the server's
+// Content-Disposition header, else the last segment of the url path, else
+// index.html
+pub fn derive_file_name(url: &str, content_disposition: Option<&str>) -> String {
+    content_disposition
+        .and_then(parse_content_disposition)
+        .or_else(|| url_file_name(url))
+        .unwrap_or_else(|| DEFAULT_FILE_NAME.to_string())
+}
+
+// Extract the file name from a Content-Disposition value, preferring the
+// RFC 5987 encoded `filename*=charset'lang'value` form over `filename=`
+fn parse_content_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for param in split_params(value) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                // A malformed one leaves the plain name to go by
+                if let Some(encoded) = value.trim().splitn(3, '\'').nth(2) {
+                    extended = Some(percent_decode_str(encoded).decode_utf8_lossy().to_string());
+                }
+            }
+            "filename" => {
+                let value = value.trim();
+                let value = value.strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                plain = Some(value.replace("\\\"", "\""));
+            }
+            _ => (),
+        }
+    }
+    extended.and_then(|name| sanitize(&name))
+        .or_else(|| plain.and_then(|name| sanitize(&name)))
+}
+
+// The parameters of a header value, split at the `;` outside of quoted
+// strings
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+fn url_file_name(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let segment = url.path_segments()?.next_back()?;
+    sanitize(&percent_decode_str(segment).decode_utf8_lossy())
+}
+
+// Reduce a suggested name to a plain file name so it can't point outside
+// the current directory
+fn sanitize(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?;
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_from_the_server_then_the_url() {
+        let url = "https://example.com/files/archive.tar.gz?token=1";
+        assert_eq!(derive_file_name(url, Some("attachment; filename=\"report.csv\"")), "report.csv");
+        assert_eq!(derive_file_name(url, Some("attachment; filename=plain.txt")), "plain.txt");
+        // The encoded form wins over the plain one
+        let disposition = "attachment; filename=\"fallback.txt\"; filename*=UTF-8''na%C3%AFve%20file.txt";
+        assert_eq!(derive_file_name(url, Some(disposition)), "naïve file.txt");
+        assert_eq!(derive_file_name(url, Some("inline")), "archive.tar.gz");
+        assert_eq!(derive_file_name(url, None), "archive.tar.gz");
+        assert_eq!(derive_file_name("https://example.com/my%20file.bin", None), "my file.bin");
+        assert_eq!(derive_file_name("https://example.com/", None), "index.html");
+        assert_eq!(derive_file_name("https://example.com", None), "index.html");
+        assert_eq!(derive_file_name("not a url", None), "index.html");
+    }
+
+    #[test]
+    fn names_stay_in_the_current_directory() {
+        let url = "https://example.com/file.bin";
+        assert_eq!(derive_file_name(url, Some("attachment; filename=\"../../etc/passwd\"")), "passwd");
+        assert_eq!(derive_file_name(url, Some("attachment; filename=\"C:\\\\Windows\\\\evil.dll\"")), "evil.dll");
+        assert_eq!(derive_file_name(url, Some("attachment; filename*=UTF-8''..%2F..%2Fescape")), "escape");
+        assert_eq!(derive_file_name(url, Some("attachment; filename=\"..\"")), "file.bin");
+        assert_eq!(derive_file_name("https://example.com/a/..%2F..", None), "index.html");
+    }
+
+    #[test]
+    fn malformed_encoded_names_fall_back_to_the_plain_one() {
+        let url = "https://example.com/file.bin";
+        let disposition = "attachment; filename*=UTF-8na%C3%AFve.txt; filename=\"a.txt\"";
+        assert_eq!(derive_file_name(url, Some(disposition)), "a.txt");
+        assert_eq!(derive_file_name(url, Some("attachment; filename*=garbage")), "file.bin");
+    }
+
+    #[test]
+    fn quoted_names_may_contain_semicolons() {
+        let url = "https://example.com/file.bin";
+        assert_eq!(derive_file_name(url, Some("attachment; filename=\"a;b.txt\"")), "a;b.txt");
+        assert_eq!(derive_file_name(url, Some("attachment; filename=\"say \\\"hi;\\\".txt\"; size=3")), "say \"hi;\".txt");
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use anyhow::{bail, Context, Result};
+use log::debug;
+use percent_encoding::percent_decode_str;
+use rustls::ClientConfig;
+use url::Url;
+use crate::{
+    error::DownloadError,
+    ip_version::IpVersion,
+    proxy::strip_credentials,
+    tls::{self, Stream},
+    transport::{RangeResponse, RemoteInfo, Transport},
+};
+
+// Whether `url` is served by this module rather than over HTTP
+pub fn is_ftp(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "ftp" | "ftps"))
+}
+
+// Command connection to the server, replies are `<code> <text>` lines,
+// or several lines from `<code>-` to `<code> `
+struct Control {
+    reader: BufReader<Stream>,
+}
+
+impl Control {
+    fn read_reply(&mut self) -> Result<(u32, String)> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            bail!("FTP server closed the connection");
+        }
+        let code = line.get(..3)
+            .and_then(|code| code.parse().ok())
+            .with_context(|| format!("invalid FTP reply: {}", line.trim_end()))?;
+        if line.as_bytes().get(3) == Some(&b'-') {
+            let last = format!("{} ", code);
+            loop {
+                let mut next = String::new();
+                if self.reader.read_line(&mut next)? == 0 {
+                    bail!("FTP server closed the connection");
+                }
+                line.push_str(&next);
+                if next.starts_with(&last) {
+                    break;
+                }
+            }
+        }
+        Ok((code, line.get(4..).unwrap_or_default().trim_end().to_string()))
+    }
+
+    fn command(&mut self, command: &str) -> Result<(u32, String)> {
+        // Keep passwords out of the log
+        if command.starts_with("PASS ") {
+            debug!("FTP > PASS ****");
+        } else {
+            debug!("FTP > {}", command);
+        }
+        let stream = self.reader.get_mut();
+        stream.write_all(format!("{}\r\n", command).as_bytes())?;
+        stream.flush()?;
+        let (code, text) = self.read_reply()?;
+        debug!("FTP < {} {}", code, text);
+        Ok((code, text))
+    }
+
+    // Run `command`, failing unless the reply code is one of `expected`
+    fn expect(&mut self, command: &str, expected: &[u32]) -> Result<String> {
+        let (code, text) = self.command(command)?;
+        if !expected.contains(&code) {
+            let verb = command.split(' ').next().unwrap_or_default();
+            bail!("FTP {} failed: {} {}", verb, code, text);
+        }
+        Ok(text)
+    }
+}
+
+// A running RETR, reading the data connection. The control connection is
+// kept open alongside, dropping both aborts the transfer. The end of the
+// data only counts as the end of the file once the server confirms the
+// transfer with a 226, a connection dropped midway looks the same.
+struct Transfer {
+    data: Option<Stream>,
+    control: Control,
+}
+
+impl Read for Transfer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(data) = &mut self.data else {
+            return Ok(0);
+        };
+        let read = data.read(buf)?;
+        if read > 0 || buf.is_empty() {
+            return Ok(read);
+        }
+        self.data = None;
+        match self.control.read_reply() {
+            Ok((code @ (226 | 250), text)) => {
+                debug!("FTP < {} {}", code, text);
+                Ok(0)
+            }
+            Ok((code, text)) => Err(io::Error::other(format!("FTP transfer failed: {} {}", code, text))),
+            Err(err) => Err(io::Error::other(format!("FTP transfer failed: {:#}", err))),
+        }
+    }
+}
+
+// Downloads over FTP, and explicit FTPS for ftps urls. Every call has a
+// connection of its own, so workers can transfer ranges concurrently.
+#[derive(Clone, Default)]
+pub struct FtpClient {
+    // Defaults to trusting the bundled web roots
+    tls: Option<Arc<ClientConfig>>,
+    ip_version: IpVersion,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    // Reads stop waiting once it passes
+    pub deadline: Option<Instant>,
+}
+
+impl FtpClient {
+    pub fn new(
+        tls: Option<Arc<ClientConfig>>,
+        ip_version: IpVersion,
+        connect_timeout: Option<Duration>,
+        read_timeout: Option<Duration>,
+    ) -> Self {
+        FtpClient{tls, ip_version, connect_timeout, read_timeout, deadline: None}
+    }
+
+    fn connect_tcp(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let remaining = self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+        let read_timeout = match (self.read_timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+        tls::connect(host, port, self.ip_version, self.connect_timeout, read_timeout)
+    }
+
+    fn wrap_tls(&self, stream: TcpStream, host: &str) -> Result<Stream> {
+        let config = match &self.tls {
+            Some(config) => config.clone(),
+            None => Arc::new(tls::default_client_config()),
+        };
+        tls::wrap(stream, host, config)
+    }
+
+    // Log in and switch to binary mode, returning the control connection
+    // and the path of the file
+    fn login(&self, url: &Url) -> Result<(Control, String)> {
+        // IPv6 addresses without the brackets of the url
+        let host = url.host_str().context("FTP url without a host")?.trim_matches(['[', ']']);
+        let port = url.port().unwrap_or(21);
+        let secure = url.scheme() == "ftps";
+        let stream = self.connect_tcp(host, port)?;
+        let mut control = Control{reader: BufReader::new(Stream::Plain(stream))};
+        let (code, text) = control.read_reply()?;
+        if code != 220 {
+            bail!("FTP server refused the connection: {} {}", code, text);
+        }
+        if secure {
+            control.expect("AUTH TLS", &[234])?;
+            // Nothing else is sent before the handshake, the buffer is empty
+            let Stream::Plain(stream) = control.reader.into_inner() else {
+                unreachable!("control connection is already TLS");
+            };
+            control = Control{reader: BufReader::new(self.wrap_tls(stream, host)?)};
+            control.expect("PBSZ 0", &[200])?;
+            control.expect("PROT P", &[200])?;
+        }
+        let user = match url.username() {
+            "" => "anonymous".to_string(),
+            user => percent_decode_str(user).decode_utf8_lossy().to_string(),
+        };
+        let password = url.password()
+            .map_or("anonymous@".to_string(), |password| percent_decode_str(password).decode_utf8_lossy().to_string());
+        let (code, text) = control.command(&format!("USER {}", user))?;
+        match code {
+            230 => (),
+            331 => {
+                control.expect(&format!("PASS {}", password), &[230, 202])?;
+            }
+            _ => bail!("FTP login failed: {} {}", code, text),
+        }
+        control.expect("TYPE I", &[200])?;
+        // Relative to the login directory, as ftp urls are
+        let path = percent_decode_str(url.path().trim_start_matches('/')).decode_utf8_lossy().to_string();
+        Ok((control, path))
+    }
+
+    // Passive mode data connection, EPSV first and PASV for older servers.
+    // The PASV address is ignored in favour of the control host, it is
+    // often a private one behind NAT.
+    fn open_data(&self, control: &mut Control, url: &Url) -> Result<Stream> {
+        let host = url.host_str().unwrap_or_default().trim_matches(['[', ']']);
+        let port = match control.command("EPSV")? {
+            (229, text) => parse_epsv(&text).with_context(|| format!("invalid EPSV reply: {}", text))?,
+            _ => {
+                let text = control.expect("PASV", &[227])?;
+                parse_pasv(&text).with_context(|| format!("invalid PASV reply: {}", text))?
+            }
+        };
+        let stream = self.connect_tcp(host, port)?;
+        if url.scheme() == "ftps" {
+            return self.wrap_tls(stream, host);
+        }
+        Ok(Stream::Plain(stream))
+    }
+
+    // Start retrieving the file at byte `offset`
+    fn retrieve(&self, url: &str, offset: usize) -> Result<Transfer> {
+        let url = Url::parse(url)?;
+        let (mut control, path) = self.login(&url)?;
+        let data = self.open_data(&mut control, &url)?;
+        if offset > 0 {
+            control.expect(&format!("REST {}", offset), &[350])?;
+        }
+        control.expect(&format!("RETR {}", path), &[125, 150])?;
+        Ok(Transfer{data: Some(data), control})
+    }
+}
+
+// The Transport methods with the anyhow errors used inside the crate,
+// sorted into DownloadError variants on the way out
+impl FtpClient {
+    fn try_remote_info(&self, url: &str) -> Result<RemoteInfo> {
+        let parsed = Url::parse(url)?;
+        let (mut control, path) = self.login(&parsed).context("FTP request failed")?;
+        let size = match control.command(&format!("SIZE {}", path))? {
+            (213, text) => Some(text.trim().parse().with_context(|| format!("invalid FTP size: {}", text))?),
+            (code, text) => {
+                debug!("SIZE not available: {} {}", code, text);
+                None
+            }
+        };
+        let (code, text) = control.command("REST 0")?;
+        if code != 350 {
+            debug!("REST not supported: {} {}", code, text);
+        }
+        let _ = control.command("QUIT");
+        Ok(RemoteInfo{
+            url: strip_credentials(url),
+            content_length: size,
+            accept_ranges: code == 350 && size.is_some(),
+            ..RemoteInfo::default()
+        })
+    }
+
+    // The transfer runs to the end of the file, it is cut off after `end`
+    fn try_fetch_range(&self, url: &str, start: usize, end: usize, _if_range: Option<&str>) -> Result<RangeResponse> {
+        let transfer = self.retrieve(url, start)?;
+        Ok(RangeResponse::Body(Box::new(transfer.take((end - start + 1) as u64))))
+    }
+
+    fn try_fetch(&self, url: &str, _accept_encoding: bool) -> Result<Box<dyn Read>> {
+        Ok(Box::new(self.retrieve(url, 0).context("FTP download failed")?))
+    }
+}
+
+// FTP has SIZE for the length and REST to start past the first byte,
+// which are all ranges need
+impl Transport for FtpClient {
+    fn remote_info(&self, url: &str) -> std::result::Result<RemoteInfo, DownloadError> {
+        self.try_remote_info(url).map_err(DownloadError::from)
+    }
+
+    fn fetch_range(&self, url: &str, start: usize, end: usize, if_range: Option<&str>) -> std::result::Result<RangeResponse, DownloadError> {
+        self.try_fetch_range(url, start, end, if_range).map_err(DownloadError::from)
+    }
+
+    fn fetch(&self, url: &str, accept_encoding: bool) -> std::result::Result<Box<dyn Read>, DownloadError> {
+        self.try_fetch(url, accept_encoding).map_err(DownloadError::from)
+    }
+}
+
+// Port from `229 Entering Extended Passive Mode (|||<port>|)`
+fn parse_epsv(text: &str) -> Option<u16> {
+    let start = text.find("(|||")? + 4;
+    let end = start + text[start..].find('|')?;
+    text[start..end].parse().ok()
+}
+
+// Port from `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`
+fn parse_pasv(text: &str) -> Option<u16> {
+    let start = text.find('(')? + 1;
+    let end = start + text[start..].find(')')?;
+    let numbers: Vec<u8> = text[start..end].split(',').map(|n| n.trim().parse().ok()).collect::<Option<_>>()?;
+    let [_, _, _, _, high, low] = numbers[..] else {
+        return None;
+    };
+    Some(u16::from(high) * 256 + u16::from(low))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::{SocketAddr, TcpListener},
+        thread,
+    };
+    use crate::{
+        test_server::{capture_thread_logs, test_data, test_dir, worker_logs, TestServer},
+        Downloader,
+    };
+    use super::*;
+
+    // A passive mode FTP server on `bind` serving `body` at every path.
+    // `done` is the reply to a RETR once its data is sent, or instead of
+    // it for 5xx replies.
+    fn ftp_server(bind: &str, body: Vec<u8>, done: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind(bind).expect("failed to bind FTP server");
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let body = body.clone();
+                thread::spawn(move || {
+                    let _ = serve_control(stream, &body, done);
+                });
+            }
+        });
+        addr
+    }
+
+    fn serve_control(stream: TcpStream, body: &[u8], done: &str) -> io::Result<()> {
+        let ip = stream.local_addr()?.ip();
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut stream = stream;
+        let (mut offset, mut data) = (0, None);
+        stream.write_all(b"220 test server\r\n")?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let (command, argument) = line.trim_end().split_once(' ').unwrap_or((line.trim_end(), ""));
+            let reply = match command {
+                "USER" => "331 password please".to_string(),
+                "PASS" => "230 logged in".to_string(),
+                "TYPE" => "200 binary".to_string(),
+                "SIZE" => format!("213 {}", body.len()),
+                "REST" => {
+                    offset = argument.parse().unwrap();
+                    "350 restarting".to_string()
+                }
+                "EPSV" => {
+                    let listener = TcpListener::bind((ip, 0))?;
+                    let port = listener.local_addr()?.port();
+                    data = Some(listener);
+                    format!("229 Entering Extended Passive Mode (|||{}|)", port)
+                }
+                // A permanent error refuses the transfer outright
+                "RETR" if done.starts_with('5') => done.to_string(),
+                "RETR" => {
+                    let (mut connection, _) = data.take().expect("RETR without EPSV").accept()?;
+                    stream.write_all(b"150 sending\r\n")?;
+                    // Clients cut off ranges by closing the connection
+                    let _ = connection.write_all(&body[offset..]);
+                    drop(connection);
+                    done.to_string()
+                }
+                "QUIT" => {
+                    stream.write_all(b"221 bye\r\n")?;
+                    return Ok(());
+                }
+                _ => "502 not implemented".to_string(),
+            };
+            stream.write_all(format!("{}\r\n", reply).as_bytes())?;
+        }
+    }
+
+    fn read_all(mut reader: impl Read) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn parses_passive_ports() {
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (|||50123|)"), Some(50123));
+        assert_eq!(parse_pasv("Entering Passive Mode (10,0,0,1,195,203)"), Some(195 * 256 + 203));
+        assert_eq!(parse_pasv("Entering Passive Mode (10,0,0,1,195)"), None);
+    }
+
+    #[test]
+    fn fetches_files_and_ranges() {
+        let body = test_data(10_000);
+        let addr = ftp_server("127.0.0.1:0", body.clone(), "226 done");
+        let url = format!("ftp://{}/dir/file", addr);
+        let client = FtpClient::default();
+        let info = client.remote_info(&url).unwrap();
+        assert_eq!(info.content_length, Some(body.len()));
+        assert!(info.accept_ranges);
+        assert_eq!(read_all(client.fetch(&url, false).unwrap()).unwrap(), body);
+        let RangeResponse::Body(range) = client.fetch_range(&url, 1000, 1999, None).unwrap() else {
+            panic!("expected a range body");
+        };
+        assert_eq!(read_all(range).unwrap(), &body[1000..2000]);
+    }
+
+    #[test]
+    fn transfers_need_the_final_reply() {
+        let addr = ftp_server("127.0.0.1:0", test_data(1000), "426 connection closed, transfer aborted");
+        let transfer = FtpClient::default().fetch(&format!("ftp://{}/file", addr), false).unwrap();
+        let err = read_all(transfer).unwrap_err();
+        assert!(err.to_string().contains("426"), "{}", err);
+    }
+
+    #[test]
+    fn connects_to_ipv6_addresses() {
+        let Ok(listener) = TcpListener::bind("[::1]:0") else {
+            // No IPv6 loopback to test on
+            return;
+        };
+        drop(listener);
+        let body = test_data(3000);
+        let addr = ftp_server("[::1]:0", body.clone(), "226 done");
+        let url = format!("ftp://{}/file", addr);
+        assert!(url.starts_with("ftp://[::1]:"));
+        assert_eq!(read_all(FtpClient::default().fetch(&url, false).unwrap()).unwrap(), body);
+    }
+
+    #[test]
+    fn downloads_in_chunks() {
+        let body = test_data(50_000);
+        let addr = ftp_server("127.0.0.1:0", body.clone(), "226 done");
+        let output = test_dir("ftp-chunks").join("file");
+        let stats = Downloader::builder()
+            .url(&format!("ftp://{}/file", addr))
+            .file_name(&output)
+            .min_chunk_size(1)
+            .chunk_size(8000)
+            .build().unwrap()
+            .run().unwrap();
+        assert!(stats.num_chunks > 1);
+        assert_eq!(fs::read(&output).unwrap(), body);
+    }
+
+    #[test]
+    fn credentials_are_not_logged() {
+        capture_thread_logs();
+        let body = test_data(4096);
+        // Every transfer from the FTP server fails, so chunks move on to
+        // the mirror with a warning naming the FTP url
+        let addr = ftp_server("127.0.0.1:0", body.clone(), "550 file unavailable");
+        let url = format!("ftp://user:hunter2@{}/file", addr);
+        let mirror = TestServer::serving(body.clone());
+        assert_eq!(FtpClient::default().remote_info(&url).unwrap().url, format!("ftp://{}/file", addr));
+
+        let output = test_dir("ftp-credentials").join("file");
+        Downloader::builder()
+            .url(&url)
+            .mirror(&mirror.url("/file"))
+            .file_name(&output)
+            .chunk_size(1024)
+            .min_chunk_size(1)
+            .workers(1)
+            .thread_name_prefix("logged-ftp")
+            .build().unwrap()
+            .run().unwrap();
+        assert_eq!(fs::read(&output).unwrap(), body);
+        let lines = worker_logs("logged-ftp");
+        let warnings: Vec<_> = lines.iter().filter(|(_, line)| line.contains("trying the next mirror")).collect();
+        assert!(!warnings.is_empty(), "{:?}", lines);
+        assert!(warnings.iter().all(|(_, line)| line.contains(&format!("ftp://{}/file", addr))), "{:?}", warnings);
+        assert!(lines.iter().all(|(_, line)| !line.contains("hunter2")), "{:?}", lines);
+    }
+}
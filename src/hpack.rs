@@ -0,0 +1,431 @@
+use std::{collections::VecDeque, sync::OnceLock};
+use anyhow::{bail, Context, Result};
+
+// HPACK header compression (RFC 7541) as far as a client needs it. Headers
+// are sent without touching the dynamic table, responses are decoded in
+// full since servers index and Huffman code as they like.
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Huffman code and its length in bits for every byte value, EOS last
+const HUFFMAN: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28),
+    (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28),
+    (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10),
+    (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6),
+    (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7),
+    (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5),
+    (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14),
+    (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23),
+    (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23),
+    (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21),
+    (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22),
+    (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22),
+    (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23),
+    (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21),
+    (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27),
+    (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22),
+    (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27),
+    (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+// Room the peer may use for its dynamic table, the default as no other
+// size is advertised
+const MAX_TABLE_SIZE: usize = 4096;
+
+// Header block for `headers`, names already lowercase. Static table
+// entries are referred to by index, everything else is sent literally.
+pub fn encode(headers: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        let full = STATIC_TABLE.iter().position(|entry| entry.0 == name && entry.1 == value);
+        if let Some(index) = full {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|entry| entry.0 == name) {
+            // Literal without indexing, the name taken from the table
+            Some(index) => encode_integer(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+// `value` in an `prefix_bits` bit prefix after the `flags` bits
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix_bits: u32, value: usize) {
+    let max_prefix = (1 << prefix_bits) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max_prefix as u8);
+    let mut rest = value - max_prefix;
+    while rest >= 0x80 {
+        block.push((rest % 0x80) as u8 | 0x80);
+        rest /= 0x80;
+    }
+    block.push(rest as u8);
+}
+
+// Not Huffman coded, header values sent here are short
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+    encode_integer(block, 0x00, 7, value.len());
+    block.extend_from_slice(value.as_bytes());
+}
+
+// Reads the representations of one header block in order
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let (&byte, rest) = self.data.split_first().context("truncated header block")?;
+        self.data = rest;
+        Ok(byte)
+    }
+
+    // Integer in the low `prefix_bits` of `first` and the bytes following it
+    fn integer(&mut self, first: u8, prefix_bits: u32) -> Result<usize> {
+        let max_prefix = (1 << prefix_bits) - 1;
+        let mut value = (first & max_prefix as u8) as usize;
+        if value < max_prefix {
+            return Ok(value);
+        }
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift > 28 {
+                bail!("header block integer too large");
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let first = self.byte()?;
+        let len = self.integer(first, 7)?;
+        if self.data.len() < len {
+            bail!("truncated header block");
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        if first & 0x80 != 0 {
+            return Ok(String::from_utf8_lossy(&huffman_decode(bytes)?).to_string());
+        }
+        Ok(String::from_utf8_lossy(bytes).to_string())
+    }
+}
+
+// The Huffman code is canonical: codes of one length are consecutive,
+// so a code is found from the first code of its length and the symbols
+// sorted by code
+struct HuffmanTable {
+    // By code length
+    first_code: [u32; 31],
+    count: [u32; 31],
+    // Where the symbols of each length start in `symbols`
+    start: [usize; 31],
+    symbols: Vec<u16>,
+}
+
+fn huffman_table() -> &'static HuffmanTable {
+    static TABLE: OnceLock<HuffmanTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..HUFFMAN.len() as u16).collect();
+        symbols.sort_by_key(|symbol| {
+            let (code, len) = HUFFMAN[*symbol as usize];
+            (len, code)
+        });
+        let mut table = HuffmanTable{first_code: [0; 31], count: [0; 31], start: [0; 31], symbols: Vec::new()};
+        for (i, symbol) in symbols.iter().enumerate() {
+            let (code, len) = HUFFMAN[*symbol as usize];
+            let len = len as usize;
+            if table.count[len] == 0 {
+                table.first_code[len] = code;
+                table.start[len] = i;
+            }
+            table.count[len] += 1;
+        }
+        table.symbols = symbols;
+        table
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let table = huffman_table();
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut len = 0usize;
+    for byte in data {
+        for bit in (0..8).rev() {
+            code = (code << 1) | ((byte >> bit) & 1) as u32;
+            len += 1;
+            // Below the first code of the length wraps around past the count
+            let index = code.wrapping_sub(table.first_code[len]);
+            if index < table.count[len] {
+                match table.symbols[table.start[len] + index as usize] {
+                    256 => bail!("EOS in Huffman coded string"),
+                    symbol => decoded.push(symbol as u8),
+                }
+                code = 0;
+                len = 0;
+            } else if len >= 30 {
+                bail!("invalid Huffman code");
+            }
+        }
+    }
+    // What is left pads to a whole byte with the start of EOS, all ones
+    if len >= 8 || code != (1 << len) - 1 {
+        bail!("invalid Huffman padding");
+    }
+    Ok(decoded)
+}
+
+// Keeps the dynamic table of one connection in sync with the peer, so
+// every header block received on it has to go through the same decoder
+pub struct Decoder {
+    // Newest first, as indexes count
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder{table: VecDeque::new(), size: 0, max_size: MAX_TABLE_SIZE}
+    }
+}
+
+// An entry takes 32 bytes on top of its name and value
+fn entry_size(name: &str, value: &str) -> usize {
+    name.len() + value.len() + 32
+}
+
+impl Decoder {
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>> {
+        let mut fields = Fields{data: block};
+        let mut headers = Vec::new();
+        while !fields.data.is_empty() {
+            let first = fields.byte()?;
+            if first & 0x80 != 0 {
+                let index = fields.integer(first, 7)?;
+                headers.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                let header = self.literal(&mut fields, first, 6)?;
+                self.insert(header.clone());
+                headers.push(header);
+            } else if first & 0x20 != 0 {
+                let max_size = fields.integer(first, 5)?;
+                if max_size > MAX_TABLE_SIZE {
+                    bail!("dynamic table size {} over the limit of {}", max_size, MAX_TABLE_SIZE);
+                }
+                self.max_size = max_size;
+                self.evict(0);
+            } else {
+                // Without indexing and never indexed, the same to a decoder
+                headers.push(self.literal(&mut fields, first, 4)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String)> {
+        if index == 0 {
+            bail!("header index 0");
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.to_string(), value.to_string()));
+        }
+        self.table.get(index - 1 - STATIC_TABLE.len())
+            .cloned()
+            .with_context(|| format!("header index {} out of range", index))
+    }
+
+    // A literal header, the name either indexed or following literally
+    fn literal(&self, fields: &mut Fields, first: u8, prefix_bits: u32) -> Result<(String, String)> {
+        let name = match fields.integer(first, prefix_bits)? {
+            0 => fields.string()?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, fields.string()?))
+    }
+
+    fn insert(&mut self, (name, value): (String, String)) {
+        let size = entry_size(&name, &value);
+        self.evict(size);
+        // Entries larger than the whole table just empty it
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front((name, value));
+        }
+    }
+
+    // Drop the oldest entries until `room` more bytes fit
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&name, &value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn huffman_encode(data: &[u8]) -> Vec<u8> {
+        let (mut bits, mut len) = (0u64, 0);
+        let mut encoded = Vec::new();
+        for byte in data {
+            let (code, code_len) = HUFFMAN[*byte as usize];
+            bits = (bits << code_len) | code as u64;
+            len += code_len as u32;
+            while len >= 8 {
+                len -= 8;
+                encoded.push((bits >> len) as u8);
+            }
+        }
+        if len > 0 {
+            encoded.push(((bits << (8 - len)) | ((1 << (8 - len)) - 1)) as u8);
+        }
+        encoded
+    }
+
+    #[test]
+    fn decodes_huffman_strings() {
+        // RFC 7541 C.4.1
+        let encoded = [0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff];
+        assert_eq!(huffman_decode(&encoded).unwrap(), b"www.example.com");
+        let every_byte: Vec<u8> = (0..=255).collect();
+        assert_eq!(huffman_decode(&huffman_encode(&every_byte)).unwrap(), every_byte);
+        // `a` is 00011, padded with ones and then with zeros
+        assert_eq!(huffman_decode(&[0x1f]).unwrap(), b"a");
+        assert!(huffman_decode(&[0x18]).is_err());
+        // A whole byte of padding and EOS
+        assert!(huffman_decode(&[0xff]).is_err());
+        assert!(huffman_decode(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let headers = vec![
+            (":method".to_string(), "GET".to_string()),
+            (":path".to_string(), "/file?x=1".to_string()),
+            ("range".to_string(), "bytes=0-99".to_string()),
+        ];
+        assert_eq!(Decoder::default().decode(&encode(&headers)).unwrap(), headers);
+    }
+
+    #[test]
+    fn keeps_the_dynamic_table() {
+        // RFC 7541 C.3, requests without Huffman coding on one connection
+        let mut decoder = Decoder::default();
+        let first = b"\x82\x86\x84\x41\x0fwww.example.com";
+        let headers = decoder.decode(first).unwrap();
+        assert_eq!(headers[3], (":authority".to_string(), "www.example.com".to_string()));
+        let second = b"\x82\x86\x84\xbe\x58\x08no-cache";
+        let headers = decoder.decode(second).unwrap();
+        assert_eq!(headers[3], (":authority".to_string(), "www.example.com".to_string()));
+        assert_eq!(headers[4], ("cache-control".to_string(), "no-cache".to_string()));
+        assert!(decoder.decode(b"\xc0").is_err());
+    }
+}
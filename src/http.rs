@@ -0,0 +1,385 @@
+use std::{io::Read, time::{Duration, Instant}};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use crate::{
+    error::DownloadError,
+    multipart::{multipart_boundary, Multipart},
+    proxy::strip_credentials,
+    transport::{RangeResponse, RangesResponse, RemoteInfo, Transport},
+};
+
+// The strong ETag of a response or else its Last-Modified date, the
+// values If-Range accepts
+pub fn validator(etag: Option<&str>, last_modified: Option<&str>) -> Option<String> {
+    etag
+        .filter(|etag| !etag.starts_with("W/"))
+        .or(last_modified)
+        .map(str::to_string)
+}
+
+// What `response` tells about the file, `length` being the header with
+// its total size. Ranges count as supported when advertised.
+fn response_info(response: &ureq::Response, length: Option<&str>) -> Result<RemoteInfo> {
+    let accept_ranges = response.header("accept-ranges")
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("bytes"));
+    Ok(RemoteInfo{
+        url: strip_credentials(response.get_url()),
+        content_length: length.map(parse_length).transpose()?,
+        accept_ranges,
+        validator: validator(response.header("etag"), response.header("last-modified")),
+        etag: response.header("etag").map(str::to_string),
+        last_modified: response.header("last-modified").map(str::to_string),
+        content_disposition: response.header("content-disposition").map(str::to_string),
+        content_encoding: content_encoding(response.header("content-encoding")),
+        not_modified: false,
+    })
+}
+
+// Left over when the redirect limit is reached
+fn is_redirect(response: &ureq::Response) -> bool {
+    (300..400).contains(&response.status())
+}
+
+// Content-Encoding `value` if the body is encoded at all
+pub fn content_encoding(value: Option<&str>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| !value.is_empty() && value != "identity")
+}
+
+pub fn parse_length(value: &str) -> Result<usize> {
+    value.trim().parse::<usize>()
+        .with_context(|| format!("invalid content length: {}", value))
+}
+
+// The `<length>` of a `Content-Range: bytes <start>-<end>/<length>`
+// value, None when it is `*` for unknown
+pub fn content_range_length(value: &str) -> Option<&str> {
+    value.rsplit('/').next().filter(|length| length.trim() != "*")
+}
+
+// Parse a `Content-Range: bytes <start>-<end>/<length>` value
+pub fn parse_content_range(value: &str) -> Option<(usize, usize)> {
+    let (range, _length) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+}
+
+// HTTP(S) through ureq, the agent carries the connection pool, proxy, TLS
+// and timeout settings
+pub struct UreqTransport {
+    pub agent: ureq::Agent,
+    // Value of the Authorization header sent with every request
+    pub authorization: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub max_redirects: u32,
+    // Requests fail once it passes, even in the middle of a stalled body
+    pub deadline: Option<Instant>,
+    // HTTP date sent as If-Modified-Since when asking about the file
+    pub if_modified_since: Option<String>,
+}
+
+impl UreqTransport {
+    // Request with everything every request needs
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let mut request = self.agent.request(method, url);
+        for (name, value) in self.headers.iter() {
+            request = request.set(name, value);
+        }
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            request = request.timeout(remaining.max(Duration::from_millis(1)));
+        }
+        request
+    }
+
+    // A request about the file, conditional if asked to
+    fn probe_request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.request(method, url);
+        match &self.if_modified_since {
+            Some(date) => request.set("If-Modified-Since", date),
+            None => request,
+        }
+    }
+}
+
+// Nothing to download, the file is still the one the client has
+fn not_modified(response: &ureq::Response) -> RemoteInfo {
+    let url = strip_credentials(response.get_url());
+    debug!("server reports {} as not modified", url);
+    RemoteInfo{url, not_modified: true, ..RemoteInfo::default()}
+}
+
+// The Transport methods with the anyhow errors used inside the crate,
+// sorted into DownloadError variants on the way out
+impl UreqTransport {
+    // Ask for the length with a HEAD request so no body is transferred,
+    // then settle range support with a one byte ranged GET, servers don't
+    // always do what their accept-ranges header says. The ranged response
+    // reports the total size, which is checked against the HEAD one.
+    fn try_remote_info(&self, url: &str) -> Result<RemoteInfo> {
+        let head = match self.probe_request("HEAD", url).call() {
+            Ok(response) if response.status() == 304 => return Ok(not_modified(&response)),
+            Ok(response) if is_redirect(&response) => {
+                bail!("stopped after {} redirects at {}", self.max_redirects, strip_credentials(response.get_url()));
+            }
+            Ok(response) => Some(response_info(&response, response.header("content-length"))?),
+            Err(ureq::Error::Status(405, _)) => {
+                debug!("HEAD not allowed, probing with GET");
+                None
+            }
+            Err(err) => {
+                return Err(err).context("HEAD request failed");
+            }
+        };
+        let head_length = head.as_ref().and_then(|head| head.content_length);
+        if let (Some(head), Some(0)) = (&head, head_length) {
+            // Nothing to ask a range of
+            return Ok(head.clone());
+        }
+        let response = match self.probe_request("GET", url).set("Range", "bytes=0-0").call() {
+            Ok(response) => response,
+            Err(err) => match head {
+                Some(head) => {
+                    warn!("range probe failed, going by the HEAD response: {}", err);
+                    return Ok(head);
+                }
+                None => return Err(err).context("GET request failed"),
+            },
+        };
+        if response.status() == 304 {
+            return Ok(not_modified(&response));
+        }
+        if is_redirect(&response) {
+            bail!("stopped after {} redirects at {}", self.max_redirects, strip_credentials(response.get_url()));
+        }
+        // A ranged response reports the full length as `bytes 0-0/<length>`,
+        // a server ignoring the range sends the whole body
+        let ranged = response.status() == 206;
+        let length = if ranged {
+            response.header("content-range").and_then(content_range_length)
+        } else {
+            response.header("content-length")
+        };
+        let mut info = response_info(&response, length)?;
+        match (ranged, info.content_length) {
+            (true, Some(length)) => info!("byte ranges supported, Content-Range reports {} bytes", length),
+            (true, None) => debug!("Content-Range doesn't tell the total size"),
+            (false, _) => info!("range probe answered with status {}, byte ranges unsupported", response.status()),
+        }
+        if let (Some(head_length), Some(length)) = (head_length, info.content_length) {
+            if head_length != length {
+                warn!("HEAD reports {} bytes but the range probe {}, going by the range probe", head_length, length);
+            }
+        }
+        info.content_length = info.content_length.or(head_length);
+        // Ranges are no use without knowing where the file ends
+        info.accept_ranges = ranged && info.content_length.is_some();
+        Ok(info)
+    }
+
+    fn try_fetch_range(&self, url: &str, start: usize, end: usize, if_range: Option<&str>) -> Result<RangeResponse> {
+        let mut request = self.request("GET", url)
+            .set("Range", format!("bytes={}-{}", start, end).as_str());
+        if let Some(validator) = if_range {
+            request = request.set("If-Range", validator);
+        }
+        let response = match request.call() {
+            Err(ureq::Error::Status(416, _)) => return Ok(RangeResponse::NotSatisfiable),
+            response => response?,
+        };
+        if response.status() == 200 && if_range.is_some() {
+            return Ok(RangeResponse::Changed);
+        }
+        if response.status() != 206 {
+            bail!("expected partial content, got status {}", response.status());
+        }
+        // Don't trust the body unless it is exactly the requested range,
+        // proxies and misbehaving servers may return another one
+        let range = response.header("content-range").and_then(parse_content_range);
+        if range != Some((start, end)) {
+            bail!("range mismatch: requested {}-{}, got {:?}", start, end, response.header("content-range"));
+        }
+        Ok(RangeResponse::Body(response.into_reader()))
+    }
+
+    fn try_fetch_ranges(&self, url: &str, ranges: &[(usize, usize)], if_range: Option<&str>) -> Result<RangesResponse> {
+        let ranges: Vec<String> = ranges.iter().map(|(start, end)| format!("{}-{}", start, end)).collect();
+        let mut request = self.request("GET", url)
+            .set("Range", format!("bytes={}", ranges.join(",")).as_str());
+        if let Some(validator) = if_range {
+            request = request.set("If-Range", validator);
+        }
+        let response = request.call()?;
+        // The whole file in answer to If-Range is a newer one, whether or
+        // not the server could have sent the parts
+        if response.status() == 200 && if_range.is_some() {
+            return Ok(RangesResponse::Changed);
+        }
+        let boundary = response.header("content-type")
+            .filter(|_| response.status() == 206)
+            .and_then(multipart_boundary);
+        let Some(boundary) = boundary else {
+            debug!("multi-range request answered with status {} and {:?}", response.status(), response.header("content-type"));
+            return Ok(RangesResponse::Unsupported);
+        };
+        Ok(RangesResponse::Parts(Multipart::new(response.into_reader(), &boundary)))
+    }
+
+    fn try_fetch(&self, url: &str, accept_encoding: bool) -> Result<Box<dyn Read>> {
+        let mut request = self.request("GET", url);
+        if accept_encoding {
+            request = request.set("Accept-Encoding", "gzip, deflate");
+        }
+        Ok(request.call().context("GET request failed")?.into_reader())
+    }
+}
+
+impl Transport for UreqTransport {
+    fn remote_info(&self, url: &str) -> std::result::Result<RemoteInfo, DownloadError> {
+        self.try_remote_info(url).map_err(DownloadError::from)
+    }
+
+    fn fetch_range(&self, url: &str, start: usize, end: usize, if_range: Option<&str>) -> std::result::Result<RangeResponse, DownloadError> {
+        self.try_fetch_range(url, start, end, if_range).map_err(DownloadError::from)
+    }
+
+    fn fetch_ranges(&self, url: &str, ranges: &[(usize, usize)], if_range: Option<&str>) -> std::result::Result<RangesResponse, DownloadError> {
+        self.try_fetch_ranges(url, ranges, if_range).map_err(DownloadError::from)
+    }
+
+    fn fetch(&self, url: &str, accept_encoding: bool) -> std::result::Result<Box<dyn Read>, DownloadError> {
+        self.try_fetch(url, accept_encoding).map_err(DownloadError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::DownloadError,
+        test_server::{serve_file, test_data, Response, TestServer},
+    };
+    use super::*;
+
+    fn transport() -> UreqTransport {
+        UreqTransport{
+            agent: ureq::agent(),
+            authorization: None,
+            headers: Vec::new(),
+            max_redirects: 5,
+            deadline: None,
+            if_modified_since: None,
+        }
+    }
+
+    fn body(response: RangeResponse) -> Vec<u8> {
+        let RangeResponse::Body(mut body) = response else {
+            panic!("expected a body");
+        };
+        let mut bytes = Vec::new();
+        body.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn error_statuses_are_http_errors() {
+        let server = TestServer::new(|_| Response::new(404));
+        let err = transport().fetch_range(&server.url("/file"), 0, 9, None).err().unwrap();
+        assert!(matches!(&err, DownloadError::Http{source, ..} if matches!(source.as_ref(), ureq::Error::Status(404, _))));
+        let err = transport().fetch(&server.url("/file"), false).err().unwrap();
+        assert!(matches!(&err, DownloadError::Http{context, ..} if context == "GET request failed"));
+    }
+
+    #[test]
+    fn unreachable_servers_are_transport_errors() {
+        // Bound and dropped, so nothing listens there
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let err = transport().remote_info(&format!("http://{}/file", addr)).err().unwrap();
+        assert!(matches!(&err, DownloadError::Http{source, ..} if matches!(source.as_ref(), ureq::Error::Transport(_))));
+    }
+
+    #[test]
+    fn range_responses() {
+        let data = test_data(1000);
+        let server_data = data.clone();
+        let server = TestServer::new(move |request| serve_file(request, &server_data, Some("\"v1\"")));
+        let url = server.url("/file");
+        assert_eq!(body(transport().fetch_range(&url, 100, 199, None).unwrap()), &data[100..200]);
+        assert_eq!(body(transport().fetch_range(&url, 100, 199, Some("\"v1\"")).unwrap()), &data[100..200]);
+        assert!(matches!(transport().fetch_range(&url, 100, 199, Some("\"v0\"")).unwrap(), RangeResponse::Changed));
+        assert!(matches!(transport().fetch_range(&url, 1000, 1099, None).unwrap(), RangeResponse::NotSatisfiable));
+    }
+
+    #[test]
+    fn mismatched_and_short_ranges_are_errors() {
+        let data = test_data(1000);
+        let server = TestServer::new(move |request| {
+            let (start, end) = request.range().unwrap();
+            match request.path.as_str() {
+                // One byte off
+                "/shifted" => Response::new(206)
+                    .header("Content-Range", &format!("bytes {}-{}/1000", start + 1, end + 1))
+                    .body(&data[start + 1..end + 2]),
+                // Claims the range but sends less
+                _ => Response::new(206)
+                    .header("Content-Range", &format!("bytes {}-{}/1000", start, end))
+                    .header("Content-Length", &(end - start).to_string())
+                    .body(&data[start..end]),
+            }
+        });
+        let err = transport().fetch_range(&server.url("/shifted"), 100, 199, None).err().unwrap();
+        assert!(matches!(&err, DownloadError::Other(_)));
+        assert!(err.to_string().starts_with("range mismatch"), "{}", err);
+        // The body ends early, counted by the caller
+        assert_eq!(body(transport().fetch_range(&server.url("/short"), 100, 199, None).unwrap()).len(), 99);
+    }
+
+    #[test]
+    fn multi_range_responses() {
+        let data = test_data(1000);
+        let server_data = data.clone();
+        let server = TestServer::new(move |request| serve_file(request, &server_data, Some("\"v1\"")));
+        let url = server.url("/file");
+        let RangesResponse::Parts(mut parts) = transport().fetch_ranges(&url, &[(0, 9), (500, 509)], None).unwrap() else {
+            panic!("expected multipart parts");
+        };
+        assert_eq!(parts.next_part().unwrap(), Some((0, 9)));
+        let mut part = Vec::new();
+        parts.read_to_end(&mut part).unwrap();
+        assert_eq!(part, &data[..10]);
+        assert_eq!(parts.next_part().unwrap(), Some((500, 509)));
+        assert!(matches!(transport().fetch_ranges(&url, &[(0, 9), (500, 509)], Some("\"v0\"")).unwrap(), RangesResponse::Changed));
+        let whole = TestServer::new(|_| Response::new(200).body("whole file"));
+        assert!(matches!(transport().fetch_ranges(&whole.url("/file"), &[(0, 1), (3, 4)], None).unwrap(), RangesResponse::Unsupported));
+    }
+
+    #[test]
+    fn length_from_head_or_the_range_probe() {
+        let data = test_data(1000);
+        let server_data = data.clone();
+        let server = TestServer::new(move |request| match (request.path.as_str(), request.method.as_str()) {
+            ("/no-head", "HEAD") => Response::new(405),
+            _ => serve_file(request, &server_data, None),
+        });
+        let info = transport().remote_info(&server.url("/file")).unwrap();
+        assert_eq!((info.content_length, info.accept_ranges), (Some(1000), true));
+        let info = transport().remote_info(&server.url("/no-head")).unwrap();
+        assert_eq!((info.content_length, info.accept_ranges), (Some(1000), true));
+        let requests: Vec<_> = server.requests().iter()
+            .map(|request| (request.path.clone(), request.method.clone(), request.header("range").map(str::to_string)))
+            .collect();
+        let probe = Some("bytes=0-0".to_string());
+        assert_eq!(requests, [
+            ("/file".to_string(), "HEAD".to_string(), None),
+            ("/file".to_string(), "GET".to_string(), probe.clone()),
+            ("/no-head".to_string(), "HEAD".to_string(), None),
+            ("/no-head".to_string(), "GET".to_string(), probe),
+        ]);
+        let refused = TestServer::new(|_| Response::new(403));
+        let err = transport().remote_info(&refused.url("/file")).err().unwrap();
+        assert!(matches!(&err, DownloadError::Http{context, ..} if context == "HEAD request failed"), "{}", err);
+    }
+}
@@ -0,0 +1,1065 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use rustls::ClientConfig;
+use url::{Position, Url};
+use crate::{
+    error::DownloadError,
+    hpack,
+    ip_version::IpVersion,
+    http::{content_encoding, content_range_length, parse_content_range, parse_length, validator, UreqTransport},
+    proxy::strip_credentials,
+    tls::{self, Stream},
+    transport::{RangeResponse, RemoteInfo, StatusError, Transport},
+};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+const CANCEL: u32 = 0x8;
+
+// Largest frame either side may send without agreeing on more, all
+// this client takes
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+// Largest frame size a server can allow
+const MAX_ALLOWED_FRAME_SIZE: u32 = (1 << 24) - 1;
+// What a stream may receive before it is read, this bounds how much a
+// slow reader buffers
+const STREAM_WINDOW: u32 = 4 * 1024 * 1024;
+// Stream windows do the bounding, the connection one is credited as data arrives
+const CONNECTION_WINDOW: u32 = 1 << 30;
+const DEFAULT_WINDOW: u32 = 65_535;
+const MAX_WINDOW: u32 = (1 << 31) - 1;
+
+// Any of these would be a protocol error over HTTP/2
+const CONNECTION_HEADERS: [&str; 6] = ["connection", "host", "keep-alive", "proxy-connection", "te", "transfer-encoding"];
+
+fn frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn be_u32(bytes: &[u8]) -> Result<u32> {
+    Ok(u32::from_be_bytes(bytes.get(..4).context("truncated frame")?.try_into()?))
+}
+
+// Frame payload without its padding
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&padding, rest) = payload.split_first().context("truncated frame")?;
+    rest.get(..rest.len().saturating_sub(padding as usize))
+        .filter(|_| padding as usize <= rest.len())
+        .context("padding longer than the frame")
+}
+
+// From the connection thread to the stream it belongs to
+enum Event {
+    Headers(Vec<(String, String)>),
+    Data(Vec<u8>),
+    End,
+    Error(String),
+}
+
+// From streams and the socket reader to the connection thread
+enum Command {
+    Open{id: u32, block: Vec<u8>, events: Sender<Event>},
+    // A stream read this many bytes, so the server can send more
+    Consumed{id: u32, len: u32},
+    Reset{id: u32},
+    // Read from the socket, empty once the server closed it
+    Received(io::Result<Vec<u8>>),
+    // The last handle on the connection is gone
+    Close,
+}
+
+struct Commands {
+    sender: Sender<Command>,
+    next_id: u32,
+}
+
+// Handle on a connection thread. Stream ids are handed out under the lock
+// so streams reach the thread in the order HTTP/2 requires.
+struct Connection {
+    commands: Mutex<Commands>,
+    // Set once the connection takes no new streams
+    closed: Arc<AtomicBool>,
+    // Why the connection thread stopped, if it failed
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl Connection {
+    fn open(&self, block: Vec<u8>) -> Result<(u32, Receiver<Event>)> {
+        let (events, receiver) = mpsc::channel();
+        let mut commands = self.commands.lock().unwrap();
+        let id = commands.next_id;
+        commands.next_id += 2;
+        commands.sender.send(Command::Open{id, block, events})
+            .map_err(|_| self.closed_error())?;
+        Ok((id, receiver))
+    }
+
+    // For streams that lost the connection thread before it could tell
+    // them why
+    fn closed_error(&self) -> io::Error {
+        let failure = self.failure.lock().unwrap();
+        io::Error::other(failure.as_deref().unwrap_or("HTTP/2 connection closed"))
+    }
+
+    fn send(&self, command: Command) {
+        let _ = self.commands.lock().unwrap().sender.send(command);
+    }
+}
+
+// The socket reader holds on to a sender too, so the thread has to be
+// told
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.send(Command::Close);
+    }
+}
+
+struct Active {
+    events: Sender<Event>,
+    // Read since the window was last updated
+    consumed: u32,
+}
+
+// Owns the socket of one connection, multiplexing the streams of every
+// worker over it
+struct ConnectionThread {
+    stream: Stream,
+    commands: Receiver<Command>,
+    closed: Arc<AtomicBool>,
+    failure: Arc<Mutex<Option<String>>>,
+    decoder: hpack::Decoder,
+    streams: HashMap<u32, Active>,
+    // Waiting for the server to allow more concurrent streams
+    queued: VecDeque<(u32, Vec<u8>, Sender<Event>)>,
+    max_concurrent: usize,
+    // What the server takes, header blocks are split into frames of it
+    max_frame_size: usize,
+    // Stream id, END_STREAM and the fragments so far of a header block
+    // still waiting for CONTINUATION frames
+    continuation: Option<(u32, bool, Vec<u8>)>,
+    // Received bytes short of a whole frame
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // Connection level bytes received since the window was last updated
+    received: u32,
+    goaway: bool,
+}
+
+impl ConnectionThread {
+    fn run(mut self) {
+        if let Err(err) = self.serve() {
+            debug!("HTTP/2 connection failed: {:#}", err);
+            let message = format!("HTTP/2 connection failed: {:#}", err);
+            *self.failure.lock().unwrap() = Some(message.clone());
+            for (_, active) in self.streams.drain() {
+                let _ = active.events.send(Event::Error(message.clone()));
+            }
+            for (_, _, events) in self.queued.drain(..) {
+                let _ = events.send(Event::Error(message.clone()));
+            }
+        }
+        self.closed.store(true, Ordering::Relaxed);
+        // Wakes the socket reader
+        let _ = self.stream.tcp().shutdown(Shutdown::Both);
+    }
+
+    // Sleeps until a stream or the socket has something, then takes in
+    // every command waiting before writing
+    fn serve(&mut self) -> Result<()> {
+        loop {
+            let Ok(command) = self.commands.recv() else {
+                return Ok(());
+            };
+            let mut next = Some(command);
+            while let Some(command) = next {
+                match command {
+                    // Nothing left that could use the connection
+                    Command::Close => return Ok(()),
+                    Command::Received(Ok(data)) if data.is_empty() => bail!("closed by the server"),
+                    Command::Received(Ok(data)) => {
+                        self.stream.receive(&data, &mut self.input)?;
+                        self.frames()?;
+                    }
+                    Command::Received(Err(err)) => return Err(err.into()),
+                    command => self.command(command),
+                }
+                next = self.commands.try_recv().ok();
+            }
+            while self.streams.len() < self.max_concurrent {
+                let Some((id, block, events)) = self.queued.pop_front() else {
+                    break;
+                };
+                self.write_headers(id, &block);
+                self.streams.insert(id, Active{events, consumed: 0});
+            }
+            if !self.output.is_empty() {
+                self.stream.write_all(&self.output)?;
+                self.stream.flush()?;
+                self.output.clear();
+            }
+            if self.goaway && self.streams.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command(&mut self, command: Command) {
+        match command {
+            Command::Open{events, ..} if self.goaway => {
+                let _ = events.send(Event::Error("HTTP/2 connection closed by the server".to_string()));
+            }
+            Command::Open{id, block, events} => self.queued.push_back((id, block, events)),
+            Command::Consumed{id, len} => {
+                let Some(active) = self.streams.get_mut(&id) else {
+                    return;
+                };
+                active.consumed += len;
+                if active.consumed >= STREAM_WINDOW / 2 {
+                    let increment = active.consumed;
+                    active.consumed = 0;
+                    self.output.extend(frame(WINDOW_UPDATE, 0, id, &increment.to_be_bytes()));
+                }
+            }
+            Command::Reset{id} => {
+                if self.streams.remove(&id).is_some() {
+                    self.output.extend(frame(RST_STREAM, 0, id, &CANCEL.to_be_bytes()));
+                } else {
+                    self.queued.retain(|(queued, _, _)| *queued != id);
+                }
+            }
+            Command::Received(_) | Command::Close => unreachable!("handled by serve"),
+        }
+    }
+
+    // HEADERS, followed by CONTINUATION frames if the block doesn't fit one
+    fn write_headers(&mut self, id: u32, block: &[u8]) {
+        let mut fragments = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = END_STREAM;
+        loop {
+            let fragment = fragments.next().unwrap_or_default();
+            let last = fragments.peek().is_none();
+            if last {
+                flags |= END_HEADERS;
+            }
+            self.output.extend(frame(kind, flags, id, fragment));
+            if last {
+                return;
+            }
+            kind = CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    fn frames(&mut self) -> Result<()> {
+        while self.input.len() >= 9 {
+            let len = u32::from_be_bytes([0, self.input[0], self.input[1], self.input[2]]) as usize;
+            // Never allowed more, it would only be buffered
+            if len > MAX_FRAME_SIZE {
+                bail!("frame of {} bytes is over the maximum frame size", len);
+            }
+            if self.input.len() < 9 + len {
+                break;
+            }
+            let frame: Vec<u8> = self.input.drain(..9 + len).collect();
+            let id = be_u32(&frame[5..9])? & 0x7fff_ffff;
+            self.frame(frame[3], frame[4], id, &frame[9..])?;
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) -> Result<()> {
+        if self.continuation.is_some() && kind != CONTINUATION {
+            bail!("expected a CONTINUATION frame, got type {}", kind);
+        }
+        match kind {
+            DATA => {
+                self.received += payload.len() as u32;
+                if self.received >= CONNECTION_WINDOW / 2 {
+                    self.output.extend(frame(WINDOW_UPDATE, 0, 0, &self.received.to_be_bytes()));
+                    self.received = 0;
+                }
+                let data = unpad(flags, payload)?;
+                if let Some(active) = self.streams.get_mut(&id) {
+                    // Padding counts against the window without ever being read
+                    active.consumed += (payload.len() - data.len()) as u32;
+                    if !data.is_empty() {
+                        let _ = active.events.send(Event::Data(data.to_vec()));
+                    }
+                }
+                if flags & END_STREAM != 0 {
+                    self.end(id);
+                }
+            }
+            HEADERS => {
+                let mut fragment = unpad(flags, payload)?;
+                if flags & PRIORITY != 0 {
+                    fragment = fragment.get(5..).context("truncated HEADERS frame")?;
+                }
+                let end_stream = flags & END_STREAM != 0;
+                if flags & END_HEADERS != 0 {
+                    self.headers(id, end_stream, fragment)?;
+                } else {
+                    self.continuation = Some((id, end_stream, fragment.to_vec()));
+                }
+            }
+            CONTINUATION => {
+                let Some((stream_id, end_stream, mut block)) = self.continuation.take() else {
+                    bail!("CONTINUATION frame without HEADERS");
+                };
+                if stream_id != id {
+                    bail!("CONTINUATION frame for another stream");
+                }
+                block.extend_from_slice(payload);
+                if flags & END_HEADERS != 0 {
+                    self.headers(id, end_stream, &block)?;
+                } else {
+                    self.continuation = Some((id, end_stream, block));
+                }
+            }
+            RST_STREAM => {
+                let code = be_u32(payload)?;
+                if let Some(active) = self.streams.remove(&id) {
+                    let _ = active.events.send(Event::Error(format!("stream reset by the server, error code {}", code)));
+                }
+            }
+            SETTINGS if flags & ACK == 0 => {
+                for setting in payload.chunks_exact(6) {
+                    let value = be_u32(&setting[2..])?;
+                    match u16::from_be_bytes([setting[0], setting[1]]) {
+                        SETTINGS_MAX_CONCURRENT_STREAMS => {
+                            debug!("server allows {} concurrent HTTP/2 streams", value);
+                            self.max_concurrent = value as usize;
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => {
+                            if !(MAX_FRAME_SIZE as u32..=MAX_ALLOWED_FRAME_SIZE).contains(&value) {
+                                bail!("invalid HTTP/2 maximum frame size {}", value);
+                            }
+                            debug!("server takes HTTP/2 frames of up to {} bytes", value);
+                            self.max_frame_size = value as usize;
+                        }
+                        // Windows only limit DATA frames, requests send none
+                        SETTINGS_INITIAL_WINDOW_SIZE if value > MAX_WINDOW => {
+                            bail!("invalid HTTP/2 initial window size {}", value);
+                        }
+                        _ => (),
+                    }
+                }
+                self.output.extend(frame(SETTINGS, ACK, 0, &[]));
+            }
+            PING if flags & ACK == 0 => {
+                self.output.extend(frame(PING, ACK, 0, payload));
+            }
+            GOAWAY => {
+                let last_id = be_u32(payload)? & 0x7fff_ffff;
+                let code = be_u32(payload.get(4..).unwrap_or_default())?;
+                debug!("server is closing the HTTP/2 connection after stream {}, error code {}", last_id, code);
+                self.goaway = true;
+                self.closed.store(true, Ordering::Relaxed);
+                // Streams past the last one were never processed, they can be retried
+                let message = "HTTP/2 connection closed by the server".to_string();
+                for id in self.streams.keys().copied().filter(|id| *id > last_id).collect::<Vec<_>>() {
+                    let _ = self.streams.remove(&id).unwrap().events.send(Event::Error(message.clone()));
+                }
+                for (_, _, events) in self.queued.drain(..) {
+                    let _ = events.send(Event::Error(message.clone()));
+                }
+            }
+            PUSH_PROMISE => bail!("server push though it is disabled"),
+            // Settings and ping acknowledgements, priorities and send
+            // windows, nothing a client that only sends headers needs
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Decoded even for streams already given up on, the decoder has to see
+    // every header block to keep its table in sync
+    fn headers(&mut self, id: u32, end_stream: bool, block: &[u8]) -> Result<()> {
+        let headers = self.decoder.decode(block)?;
+        let informational = headers.iter()
+            .any(|(name, value)| name == ":status" && value.starts_with('1'));
+        if let Some(active) = self.streams.get(&id).filter(|_| !informational) {
+            let _ = active.events.send(Event::Headers(headers));
+        }
+        if end_stream {
+            self.end(id);
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, id: u32) {
+        if let Some(active) = self.streams.remove(&id) {
+            let _ = active.events.send(Event::End);
+        }
+    }
+}
+
+// Pass on what arrives on the socket of a connection until it is closed
+fn read_socket(mut socket: TcpStream, commands: Sender<Command>) {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let received = match socket.read(&mut buf) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            received => received.map(|len| buf[..len].to_vec()),
+        };
+        let closed = !matches!(&received, Ok(data) if !data.is_empty());
+        if commands.send(Command::Received(received)).is_err() || closed {
+            return;
+        }
+    }
+}
+
+// Wait at most `read_timeout` and never past `deadline`
+fn wait_time(read_timeout: Option<Duration>, deadline: Option<Instant>) -> Option<Duration> {
+    let remaining = deadline
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+    match (read_timeout, remaining) {
+        (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+        (timeout, remaining) => timeout.or(remaining),
+    }
+}
+
+fn next_event(connection: &Connection, events: &Receiver<Event>, timeout: Option<Duration>) -> io::Result<Event> {
+    let Some(timeout) = timeout else {
+        return events.recv().map_err(|_| connection.closed_error());
+    };
+    match events.recv_timeout(timeout) {
+        Ok(event) => Ok(event),
+        Err(RecvTimeoutError::Timeout) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out reading from the server")),
+        Err(RecvTimeoutError::Disconnected) => Err(connection.closed_error()),
+    }
+}
+
+// Response body, the stream is reset when dropped before its end
+struct Body {
+    connection: Arc<Connection>,
+    id: u32,
+    events: Receiver<Event>,
+    data: Vec<u8>,
+    pos: usize,
+    done: bool,
+    read_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.data.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match next_event(&self.connection, &self.events, wait_time(self.read_timeout, self.deadline))? {
+                Event::Data(data) => {
+                    self.connection.send(Command::Consumed{id: self.id, len: data.len() as u32});
+                    self.data = data;
+                    self.pos = 0;
+                }
+                // Trailers
+                Event::Headers(_) => (),
+                Event::End => self.done = true,
+                Event::Error(message) => {
+                    self.done = true;
+                    return Err(io::Error::other(message));
+                }
+            }
+        }
+        let len = buf.len().min(self.data.len() - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Drop for Body {
+    fn drop(&mut self) {
+        if !self.done {
+            self.connection.send(Command::Reset{id: self.id});
+        }
+    }
+}
+
+struct Response {
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // What the response tells about the file, `length` being the header
+    // with its total size
+    fn info(&self, length: Option<&str>) -> Result<RemoteInfo> {
+        Ok(RemoteInfo{
+            url: self.url.clone(),
+            content_length: length.map(parse_length).transpose()?,
+            accept_ranges: false,
+            validator: validator(self.header("etag"), self.header("last-modified")),
+            etag: self.header("etag").map(str::to_string),
+            last_modified: self.header("last-modified").map(str::to_string),
+            content_disposition: self.header("content-disposition").map(str::to_string),
+            content_encoding: content_encoding(self.header("content-encoding")),
+            not_modified: false,
+        })
+    }
+}
+
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+// HTTP(S) over HTTP/2, with every request to a server multiplexed onto one
+// connection instead of one connection per worker. Cleartext urls use
+// HTTP/2 with prior knowledge, https negotiates it through ALPN.
+pub struct Http2Transport {
+    // For https urls, always set so none go out in cleartext
+    tls: Arc<ClientConfig>,
+    ip_version: IpVersion,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    // Value of the Authorization header sent with every request
+    pub authorization: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub max_redirects: u32,
+    // Requests fail once it passes, even in the middle of a stalled body
+    pub deadline: Option<Instant>,
+    // HTTP date sent as If-Modified-Since when asking about the file
+    pub if_modified_since: Option<String>,
+    // By scheme, host and port, replaced once closed
+    connections: Mutex<HashMap<String, Arc<Connection>>>,
+}
+
+impl Http2Transport {
+    // Request settings are taken over from `http`
+    pub fn new(
+        tls: Option<Arc<ClientConfig>>,
+        ip_version: IpVersion,
+        connect_timeout: Option<Duration>,
+        read_timeout: Option<Duration>,
+        http: &UreqTransport,
+    ) -> Self {
+        let mut config = tls.map_or_else(tls::default_client_config, |config| config.as_ref().clone());
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Http2Transport{
+            tls: Arc::new(config),
+            ip_version,
+            connect_timeout,
+            read_timeout,
+            authorization: http.authorization.clone(),
+            headers: http.headers.clone(),
+            max_redirects: http.max_redirects,
+            deadline: http.deadline,
+            if_modified_since: http.if_modified_since.clone(),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The open connection to the server of `url`, connecting if there is
+    // none. Workers wait for the first one to connect rather than each
+    // opening their own.
+    fn connection(&self, url: &Url) -> Result<Arc<Connection>> {
+        let host = url.host_str().context("url without a host")?;
+        let port = url.port_or_known_default().context("url without a port")?;
+        let key = format!("{}://{}:{}", url.scheme(), host, port);
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(connection) = connections.get(&key).filter(|connection| !connection.closed.load(Ordering::Relaxed)) {
+            return Ok(connection.clone());
+        }
+        let tcp = tls::connect(host.trim_matches(['[', ']']), port, self.ip_version, self.connect_timeout, wait_time(self.read_timeout, self.deadline))?;
+        let mut stream = match url.scheme() {
+            "https" => tls::wrap(tcp, host.trim_matches(['[', ']']), self.tls.clone())?,
+            _ => Stream::Plain(tcp),
+        };
+        let mut settings = Vec::new();
+        settings.extend_from_slice(&SETTINGS_ENABLE_PUSH.to_be_bytes());
+        settings.extend_from_slice(&0u32.to_be_bytes());
+        settings.extend_from_slice(&SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes());
+        settings.extend_from_slice(&STREAM_WINDOW.to_be_bytes());
+        let mut preface = PREFACE.to_vec();
+        preface.extend(frame(SETTINGS, 0, 0, &settings));
+        preface.extend(frame(WINDOW_UPDATE, 0, 0, &(CONNECTION_WINDOW - DEFAULT_WINDOW).to_be_bytes()));
+        // Writing completes the TLS handshake
+        stream.write_all(&preface).with_context(|| format!("failed to connect to {}", key))?;
+        stream.flush()?;
+        if let Stream::Tls(tls) = &stream {
+            if tls.conn.alpn_protocol() != Some(b"h2") {
+                bail!("{} doesn't support HTTP/2", host);
+            }
+        }
+        // Streams time out on their own, idle connections stay open
+        stream.tcp().set_read_timeout(None)?;
+        let socket = stream.tcp().try_clone()?;
+        info!("opened HTTP/2 connection to {}", key);
+        let (sender, receiver) = mpsc::channel();
+        let reader = sender.clone();
+        thread::Builder::new()
+            .name(format!("http2-read-{}", host))
+            .spawn(move || read_socket(socket, reader))
+            .context("failed to start the HTTP/2 socket reader")?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let failure = Arc::new(Mutex::new(None));
+        let connection = ConnectionThread {
+            stream,
+            commands: receiver,
+            closed: closed.clone(),
+            failure: failure.clone(),
+            decoder: hpack::Decoder::default(),
+            streams: HashMap::new(),
+            queued: VecDeque::new(),
+            max_concurrent: usize::MAX,
+            max_frame_size: MAX_FRAME_SIZE,
+            continuation: None,
+            input: Vec::new(),
+            output: Vec::new(),
+            received: 0,
+            goaway: false,
+        };
+        thread::Builder::new()
+            .name(format!("http2-{}", host))
+            .spawn(move || connection.run())
+            .context("failed to start the HTTP/2 connection thread")?;
+        let connection = Arc::new(Connection{commands: Mutex::new(Commands{sender, next_id: 1}), closed, failure});
+        connections.insert(key, connection.clone());
+        Ok(connection)
+    }
+
+    // One GET, redirects not followed
+    fn request(&self, url: &Url, extra_headers: &[(&str, &str)], authorization: Option<&str>) -> Result<Response> {
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("HTTP/2 is not supported for {} urls", url.scheme());
+        }
+        let host = url.host_str().context("url without a host")?;
+        let mut authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let mut fields = Vec::new();
+        let mut user_agent = false;
+        let headers = self.headers.iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(authorization.map(|authorization| ("Authorization", authorization)))
+            .chain(extra_headers.iter().copied());
+        for (name, value) in headers {
+            let name = name.to_ascii_lowercase();
+            if name == "host" {
+                authority = value.to_string();
+            }
+            if CONNECTION_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            user_agent |= name == "user-agent";
+            fields.push((name, value.to_string()));
+        }
+        if !user_agent {
+            fields.push(("user-agent".to_string(), format!("parallel-downloader/{}", env!("CARGO_PKG_VERSION"))));
+        }
+        let pseudo = [
+            (":method", "GET"),
+            (":scheme", url.scheme()),
+            (":authority", &authority),
+            (":path", &url[Position::BeforePath..Position::AfterQuery]),
+        ];
+        fields.splice(0..0, pseudo.map(|(name, value)| (name.to_string(), value.to_string())));
+        let connection = self.connection(url)?;
+        let (id, events) = connection.open(hpack::encode(&fields))?;
+        let body = Body {
+            connection,
+            id,
+            events,
+            data: Vec::new(),
+            pos: 0,
+            done: false,
+            read_timeout: self.read_timeout,
+            deadline: self.deadline,
+        };
+        match next_event(&body.connection, &body.events, wait_time(self.read_timeout, self.deadline))? {
+            Event::Headers(headers) => {
+                let status = headers.iter()
+                    .find(|(name, _)| name == ":status")
+                    .and_then(|(_, status)| status.parse().ok())
+                    .context("response without a status")?;
+                Ok(Response{url: strip_credentials(url.as_str()), status, headers, body})
+            }
+            Event::Data(_) => bail!("response body before its headers"),
+            Event::End => bail!("stream ended without a response"),
+            Event::Error(message) => bail!(message),
+        }
+    }
+
+    // GET following redirects, whatever the final status
+    fn follow(&self, url: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        let mut url = Url::parse(url)?;
+        let mut authorization = self.authorization.as_deref();
+        let mut redirects = 0;
+        let response = loop {
+            let response = self.request(&url, headers, authorization)?;
+            let location = response.header("location").filter(|_| is_redirect(response.status));
+            let Some(location) = location.filter(|_| redirects < self.max_redirects) else {
+                break response;
+            };
+            let next = url.join(location).with_context(|| format!("invalid redirect location {}", location))?;
+            debug!("redirected to {}", next);
+            // Credentials are for the url they were given with
+            authorization = None;
+            url = next;
+            redirects += 1;
+        };
+        Ok(response)
+    }
+
+    // GET following redirects, failing on error statuses like ureq does
+    fn get(&self, url: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        let response = self.follow(url, headers)?;
+        if response.status >= 400 {
+            let retry_after = response.header("retry-after").map(str::to_string);
+            return Err(StatusError{url: response.url, status: response.status, retry_after}.into());
+        }
+        Ok(response)
+    }
+
+    // A request about the file, conditional if asked to
+    fn probe(&self, url: &str) -> Result<Response> {
+        let mut headers = vec![("Range", "bytes=0-0")];
+        if let Some(date) = &self.if_modified_since {
+            headers.push(("If-Modified-Since", date));
+        }
+        self.get(url, &headers)
+    }
+}
+
+// The Transport methods with the anyhow errors used inside the crate,
+// sorted into DownloadError variants on the way out
+impl Http2Transport {
+    // One ranged GET settles both the length and range support, there is
+    // no connection to save by asking with HEAD first
+    fn try_remote_info(&self, url: &str) -> Result<RemoteInfo> {
+        let response = self.probe(url).context("GET request failed")?;
+        if response.status == 304 {
+            debug!("server reports {} as not modified", response.url);
+            return Ok(RemoteInfo{url: response.url, not_modified: true, ..RemoteInfo::default()});
+        }
+        if is_redirect(response.status) {
+            bail!("stopped after {} redirects at {}", self.max_redirects, response.url);
+        }
+        let ranged = response.status == 206;
+        let length = if ranged {
+            response.header("content-range").and_then(content_range_length)
+        } else {
+            response.header("content-length")
+        };
+        let mut info = response.info(length)?;
+        match (ranged, info.content_length) {
+            (true, Some(length)) => info!("byte ranges supported, Content-Range reports {} bytes", length),
+            (true, None) => debug!("Content-Range doesn't tell the total size"),
+            (false, _) => info!("range probe answered with status {}, byte ranges unsupported", response.status),
+        }
+        info.accept_ranges = ranged && info.content_length.is_some();
+        Ok(info)
+    }
+
+    fn try_fetch_range(&self, url: &str, start: usize, end: usize, if_range: Option<&str>) -> Result<RangeResponse> {
+        let range = format!("bytes={}-{}", start, end);
+        let mut headers = vec![("Range", range.as_str())];
+        if let Some(validator) = if_range {
+            headers.push(("If-Range", validator));
+        }
+        let response = self.follow(url, &headers)?;
+        if response.status == 416 {
+            return Ok(RangeResponse::NotSatisfiable);
+        }
+        if response.status >= 400 {
+            let retry_after = response.header("retry-after").map(str::to_string);
+            return Err(StatusError{url: response.url, status: response.status, retry_after}.into());
+        }
+        if response.status == 200 && if_range.is_some() {
+            return Ok(RangeResponse::Changed);
+        }
+        if response.status != 206 {
+            bail!("expected partial content, got status {}", response.status);
+        }
+        // Don't trust the body unless it is exactly the requested range
+        let range = response.header("content-range").and_then(parse_content_range);
+        if range != Some((start, end)) {
+            bail!("range mismatch: requested {}-{}, got {:?}", start, end, response.header("content-range"));
+        }
+        Ok(RangeResponse::Body(Box::new(response.body)))
+    }
+
+    fn try_fetch(&self, url: &str, accept_encoding: bool) -> Result<Box<dyn Read>> {
+        let mut headers = Vec::new();
+        if accept_encoding {
+            headers.push(("Accept-Encoding", "gzip, deflate"));
+        }
+        Ok(Box::new(self.get(url, &headers).context("GET request failed")?.body))
+    }
+}
+
+impl Transport for Http2Transport {
+    fn remote_info(&self, url: &str) -> std::result::Result<RemoteInfo, DownloadError> {
+        self.try_remote_info(url).map_err(DownloadError::from)
+    }
+
+    fn fetch_range(&self, url: &str, start: usize, end: usize, if_range: Option<&str>) -> std::result::Result<RangeResponse, DownloadError> {
+        self.try_fetch_range(url, start, end, if_range).map_err(DownloadError::from)
+    }
+
+    fn fetch(&self, url: &str, accept_encoding: bool) -> std::result::Result<Box<dyn Read>, DownloadError> {
+        self.try_fetch(url, accept_encoding).map_err(DownloadError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::{SocketAddr, TcpListener},
+        sync::atomic::AtomicUsize,
+    };
+    use crate::{
+        test_server::{serve_file, test_data, test_dir, Request},
+        Downloader,
+    };
+    use super::*;
+
+    // With the default TLS settings, not following redirects
+    fn transport() -> Http2Transport {
+        let http = UreqTransport{
+            agent: ureq::agent(),
+            authorization: None,
+            headers: Vec::new(),
+            max_redirects: 0,
+            deadline: None,
+            if_modified_since: None,
+        };
+        Http2Transport::new(None, IpVersion::Auto, None, None, &http)
+    }
+
+    // A cleartext HTTP/2 server on a local port, serving `body` at every
+    // path like serve_file() does. It answers one request at a time and
+    // ignores flow control, bodies in tests fit the windows.
+    struct H2Server {
+        addr: SocketAddr,
+        connections: Arc<AtomicUsize>,
+        closed: Arc<AtomicUsize>,
+        // Type and length of every frame received
+        frames: Arc<Mutex<Vec<(u8, usize)>>>,
+    }
+
+    impl H2Server {
+        // `settings` go in its SETTINGS frame, bodies are sent in DATA
+        // frames of `data_frame_size`
+        fn new(body: Vec<u8>, settings: &[(u16, u32)], data_frame_size: usize) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut payload = Vec::new();
+            for (id, value) in settings {
+                payload.extend_from_slice(&id.to_be_bytes());
+                payload.extend_from_slice(&value.to_be_bytes());
+            }
+            let server = H2Server{
+                addr: listener.local_addr().unwrap(),
+                connections: Arc::default(),
+                closed: Arc::default(),
+                frames: Arc::default(),
+            };
+            let (connections, closed, frames) = (server.connections.clone(), server.closed.clone(), server.frames.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    let (body, payload, closed, frames) = (body.clone(), payload.clone(), closed.clone(), frames.clone());
+                    thread::spawn(move || {
+                        let _ = serve(stream, &body, &payload, data_frame_size, &frames);
+                        closed.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            });
+            server
+        }
+
+        fn url(&self, path: &str) -> String {
+            format!("http://{}{}", self.addr, path)
+        }
+    }
+
+    fn serve(
+        mut stream: TcpStream,
+        body: &[u8],
+        settings: &[u8],
+        data_frame_size: usize,
+        frames: &Mutex<Vec<(u8, usize)>>,
+    ) -> io::Result<()> {
+        let mut preface = [0; PREFACE.len()];
+        stream.read_exact(&mut preface)?;
+        assert_eq!(preface, PREFACE);
+        stream.write_all(&frame(SETTINGS, 0, 0, settings))?;
+        let mut decoder = hpack::Decoder::default();
+        let mut block = Vec::new();
+        loop {
+            let mut header = [0; 9];
+            stream.read_exact(&mut header)?;
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let (kind, flags, id) = (header[3], header[4], u32::from_be_bytes(header[5..].try_into().unwrap()));
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload)?;
+            frames.lock().unwrap().push((kind, len));
+            match kind {
+                SETTINGS if flags & ACK == 0 => stream.write_all(&frame(SETTINGS, ACK, 0, &[]))?,
+                HEADERS | CONTINUATION => {
+                    block.extend(payload);
+                    if flags & END_HEADERS == 0 {
+                        continue;
+                    }
+                    let fields = decoder.decode(&block).unwrap();
+                    block.clear();
+                    let field = |name: &str| fields.iter().find(|(field, _)| field == name).unwrap().1.clone();
+                    let request = Request{method: field(":method"), path: field(":path"), headers: fields.clone()};
+                    let response = serve_file(&request, body, None);
+                    let mut headers = vec![(":status".to_string(), response.status.to_string())];
+                    for (name, value) in response.headers {
+                        headers.push((name.to_ascii_lowercase(), value));
+                    }
+                    headers.push(("content-length".to_string(), response.body.len().to_string()));
+                    let end = if response.body.is_empty() { END_STREAM } else { 0 };
+                    stream.write_all(&frame(HEADERS, END_HEADERS | end, id, &hpack::encode(&headers)))?;
+                    let mut pieces = response.body.chunks(data_frame_size).peekable();
+                    while let Some(piece) = pieces.next() {
+                        let end = if pieces.peek().is_none() { END_STREAM } else { 0 };
+                        stream.write_all(&frame(DATA, end, id, piece))?;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn read_all(mut reader: impl Read) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn downloads_chunks_over_one_connection() {
+        let body = test_data(100_000);
+        let server = H2Server::new(body.clone(), &[(SETTINGS_MAX_CONCURRENT_STREAMS, 2)], MAX_FRAME_SIZE);
+        let output = test_dir("http2-chunks").join("file");
+        let stats = Downloader::builder()
+            .url(&server.url("/file"))
+            .file_name(&output)
+            .http2(true)
+            .chunk_size(10_000)
+            .min_chunk_size(1)
+            .workers(4)
+            .build().unwrap()
+            .run().unwrap();
+        assert_eq!(stats.num_chunks, 10);
+        assert_eq!(fs::read(&output).unwrap(), body);
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn connections_close_with_the_transport() {
+        let server = H2Server::new(test_data(1000), &[], MAX_FRAME_SIZE);
+        let transport = transport();
+        assert_eq!(read_all(transport.fetch(&server.url("/file"), false).unwrap()).unwrap(), test_data(1000));
+        drop(transport);
+        let started = Instant::now();
+        while server.closed.load(Ordering::SeqCst) == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "connection still open");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn header_blocks_fit_the_server_frame_size() {
+        let long = "x".repeat(20_000);
+        for max_frame_size in [16_384, 32_768] {
+            let server = H2Server::new(test_data(1000), &[(SETTINGS_MAX_FRAME_SIZE, max_frame_size)], MAX_FRAME_SIZE);
+            let mut transport = transport();
+            // Its response comes after the server's settings
+            transport.remote_info(&server.url("/file")).unwrap();
+            transport.headers = vec![("x-long".to_string(), long.clone())];
+            let info = transport.remote_info(&server.url("/file")).unwrap();
+            assert_eq!(info.content_length, Some(1000));
+            let frames = server.frames.lock().unwrap().clone();
+            let headers: Vec<usize> = frames.iter()
+                .filter(|(kind, _)| matches!(*kind, HEADERS | CONTINUATION))
+                .map(|(_, len)| *len)
+                .collect();
+            assert!(headers.iter().all(|len| *len <= max_frame_size as usize), "{:?}", headers);
+            // One for the first request, one or two for the long one
+            assert_eq!(headers.len(), if max_frame_size > 16_384 { 2 } else { 3 }, "{:?}", headers);
+        }
+    }
+
+    #[test]
+    fn rejects_frames_over_the_maximum_size() {
+        let server = H2Server::new(test_data(50_000), &[], 20_000);
+        let transport = transport();
+        let err = transport.fetch(&server.url("/file"), false)
+            .map_err(io::Error::other)
+            .and_then(read_all)
+            .unwrap_err();
+        assert!(err.to_string().contains("frame of 20000 bytes is over the maximum frame size"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let server = H2Server::new(test_data(1000), &[(SETTINGS_MAX_FRAME_SIZE, 1000)], MAX_FRAME_SIZE);
+        let err = transport().remote_info(&server.url("/file")).unwrap_err();
+        let err = format!("{:#}", anyhow::Error::from(err));
+        assert!(err.contains("invalid HTTP/2 maximum frame size 1000"), "{}", err);
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+/// Address family tried first when connecting to a dual stack host, for
+/// when one of them is much slower or broken. The other family is still
+/// tried if none of the preferred addresses connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpVersion {
+    /// The addresses in the order the resolver gives them
+    #[default]
+    Auto,
+    /// IPv4 first
+    V4,
+    /// IPv6 first
+    V6,
+}
+
+impl IpVersion {
+    // Addresses of `host:port`, the preferred family first
+    pub(crate) fn resolve(self, addr: impl ToSocketAddrs) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        match self {
+            IpVersion::Auto => {}
+            // Stable, so each family keeps the resolver's order
+            IpVersion::V4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            IpVersion::V6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
+        Ok(addrs)
+    }
+}
+
+impl FromStr for IpVersion {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "auto" => Ok(IpVersion::Auto),
+            "4" => Ok(IpVersion::V4),
+            "6" => Ok(IpVersion::V6),
+            _ => Err(format!("unknown IP version \"{}\", expected 4, 6 or auto", text)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_versions_parse_from_the_flag_values() {
+        assert_eq!("auto".parse(), Ok(IpVersion::Auto));
+        assert_eq!("4".parse(), Ok(IpVersion::V4));
+        assert_eq!("6".parse(), Ok(IpVersion::V6));
+        assert!("5".parse::<IpVersion>().unwrap_err().contains("expected 4, 6 or auto"));
+    }
+
+    #[test]
+    fn preferred_families_resolve_first() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "127.0.0.1:80", "[::2]:80", "127.0.0.2:80"]
+            .iter().map(|addr| addr.parse().unwrap()).collect();
+        let resolve = |ip_version: IpVersion| ip_version.resolve(&addrs[..]).unwrap()
+            .iter().map(|addr| addr.to_string()).collect::<Vec<_>>();
+        assert_eq!(resolve(IpVersion::Auto), ["[::1]:80", "127.0.0.1:80", "[::2]:80", "127.0.0.2:80"]);
+        assert_eq!(resolve(IpVersion::V4), ["127.0.0.1:80", "127.0.0.2:80", "[::1]:80", "[::2]:80"]);
+        assert_eq!(resolve(IpVersion::V6), ["[::1]:80", "[::2]:80", "127.0.0.1:80", "127.0.0.2:80"]);
+    }
+}
@@ -0,0 +1,48 @@
+//! Download a file over HTTP or FTP in parallel chunks using range requests.
+//!
+//! ```no_run
+//! use parallel_downloader::Downloader;
+//!
+//! let downloader = Downloader::builder()
+//!     .url("https://example.com/file.iso")
+//!     .workers(4)
+//!     .build()?;
+//! downloader.run()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+mod cancel;
+mod channel;
+mod downloader;
+mod error;
+mod file_name;
+mod ftp;
+mod hpack;
+mod http;
+mod http2;
+mod ip_version;
+mod multipart;
+mod pause;
+mod proxy;
+mod rate_limit;
+mod reader;
+mod resume;
+mod semaphore;
+#[cfg(test)]
+mod test_server;
+mod tls;
+mod transform;
+mod transport;
+mod verify;
+mod zstd;
+
+pub use cancel::CancellationToken;
+pub use downloader::{DownloadPlan, DownloadStats, Downloader, DownloaderBuilder, ProgressEvent};
+pub use error::{DownloadError, FailedChunk};
+pub use ip_version::IpVersion;
+pub use multipart::Multipart;
+pub use pause::PauseHandle;
+pub use proxy::strip_credentials;
+pub use reader::DownloadReader;
+pub use transform::Transform;
+pub use transport::{RangeResponse, RangesResponse, RemoteInfo, StatusError, Transport};
+pub use verify::verify_sha256;
@@ -1,56 +1,256 @@
-use::std::path::PathBuf;
+use::std::{ffi::OsString, io::{self, IsTerminal}, path::PathBuf, str::FromStr};
+use anyhow::{Context, Result};
 use log::LevelFilter;
 use log4rs::{
     append::{
         console::{ConsoleAppender, Target},
         file::FileAppender,
+        rolling_file::{
+            policy::compound::{roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy},
+            RollingFileAppender,
+        },
+        Append,
     },
-    encode::pattern::PatternEncoder,
+    encode::{json::JsonEncoder, pattern::PatternEncoder, Encode},
     config::{Appender, Config, Root},
     filter::threshold::ThresholdFilter,
 };
 
-pub fn build_logger(log_level: log::LevelFilter, log_path: Option<PathBuf>) -> log4rs::Handle {
+// Format of the lines written to the log file
+#[derive(Debug, Clone, Copy)]
+pub enum LogFormat {
+    Human,
+    // One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format \"{}\", expected human or json", text)),
+        }
+    }
+}
+
+// Roll the log file over once it grows past `max_size` bytes, keeping the
+// last `keep` files as <log>.0 (newest) to <log>.<keep - 1>
+#[derive(Debug, Clone, Copy)]
+pub struct LogRotation {
+    pub max_size: u64,
+    pub keep: u32,
+}
+
+fn use_color() -> bool {
+    color_enabled(std::env::var_os("NO_COLOR"), io::stderr().is_terminal())
+}
+
+// Colors only for a terminal, and not when asked not to with a NO_COLOR
+// that isn't empty, see https://no-color.org
+fn color_enabled(no_color: Option<OsString>, terminal: bool) -> bool {
+    let no_color = no_color.is_some_and(|value| !value.is_empty());
+    !no_color && terminal
+}
+
+// Console lines as log4rs prints them by default plus the thread name, e.g.
+// `worker-3`, with the level highlighted in color: errors red, warnings yellow
+fn console_pattern(color: bool) -> &'static str {
+    if color { "{d} {h({l})} [{T}] {t} - {m}{n}" } else { "{d} {l} [{T}] {t} - {m}{n}" }
+}
+
+// Encoder of the lines written to log files
+fn file_encoder(log_format: LogFormat) -> Box<dyn Encode> {
+    match log_format {
+        // Pattern: https://docs.rs/log4rs/*/log4rs/encode/pattern/index.html
+        LogFormat::Human => Box::new(PatternEncoder::new("{l} {d} [{T}] - {m}\n")),
+        LogFormat::Json => Box::new(JsonEncoder::new()),
+    }
+}
+
+pub fn build_logger(
+    log_level: log::LevelFilter,
+    log_path: Option<PathBuf>,
+    log_format: LogFormat,
+    rotation: Option<LogRotation>,
+    error_path: Option<PathBuf>,
+) -> Result<log4rs::Handle> {
+    let config = log_config(log_level, log_path, log_format, rotation, error_path)?;
+    Ok(log4rs::init_config(config)?)
+}
+
+// Fails when a log file can't be opened, e.g. in a missing directory
+fn log_config(
+    log_level: log::LevelFilter,
+    log_path: Option<PathBuf>,
+    log_format: LogFormat,
+    rotation: Option<LogRotation>,
+    error_path: Option<PathBuf>,
+) -> Result<Config> {
     // Build a stderr logger.
-    let stderr = ConsoleAppender::builder().target(Target::Stderr).build();
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new(console_pattern(use_color()))))
+        .build();
     // Log Trace level output to file where trace is the default level
     // and the programmatically specified level to stderr.
-    let config = Config::builder();
-    let config = if let Some(log_path) = log_path {
+    let mut config = Config::builder().appender(
+        Appender::builder()
+            .filter(Box::new(ThresholdFilter::new(log_level)))
+            .build("stderr", Box::new(stderr)),
+    );
+    let mut root = Root::builder().appender("stderr");
+    if let Some(log_path) = log_path {
         // Logging to log file.
-        let log_file = FileAppender::builder()
-            // Pattern: https://docs.rs/log4rs/*/log4rs/encode/pattern/index.html
-            .encoder(Box::new(PatternEncoder::new("{l} {d} - {m}\n")))
-            .build(&log_path)
-            .unwrap();
-        let appender_name = "log_file";
-        let config = config
-            .appender(Appender::builder().build(appender_name, Box::new(log_file)))
-            .appender(
-                Appender::builder()
-                    .filter(Box::new(ThresholdFilter::new(log_level)))
-                    .build("stderr", Box::new(stderr)),
-            )
-            .build(
-                Root::builder()
-                    .appender(appender_name)
-                    .appender("stderr")
-                    .build(LevelFilter::Trace),
-            )
-            .unwrap();
-        config
-    } else {
-        config.appender(
+        let encoder = file_encoder(log_format);
+        let log_file: Box<dyn Append> = match rotation {
+            Some(rotation) => {
+                let pattern = format!("{}.{{}}", log_path.display());
+                let roller = FixedWindowRoller::builder()
+                    .build(&pattern, rotation.keep)
+                    .with_context(|| format!("invalid log file name {}", log_path.display()))?;
+                let policy = CompoundPolicy::new(
+                    Box::new(SizeTrigger::new(rotation.max_size)),
+                    Box::new(roller),
+                );
+                Box::new(RollingFileAppender::builder()
+                    .encoder(encoder)
+                    .build(&log_path, Box::new(policy))
+                    .with_context(|| format!("failed to open log file {}", log_path.display()))?)
+            }
+            None => {
+                Box::new(FileAppender::builder()
+                    .encoder(encoder)
+                    .build(&log_path)
+                    .with_context(|| format!("failed to open log file {}", log_path.display()))?)
+            }
+        };
+        config = config.appender(Appender::builder().build("log_file", log_file));
+        root = root.appender("log_file");
+    }
+    // Errors only, whatever the level of the other appenders
+    if let Some(error_path) = error_path {
+        let error_file = FileAppender::builder()
+            .encoder(file_encoder(log_format))
+            .build(&error_path)
+            .with_context(|| format!("failed to open error log file {}", error_path.display()))?;
+        config = config.appender(
             Appender::builder()
-                .filter(Box::new(ThresholdFilter::new(log_level)))
-                .build("stderr", Box::new(stderr)),
-        )
-        .build(
-            Root::builder()
-                .appender("stderr")
-                .build(LevelFilter::Trace),
-        )
-        .unwrap()
-    };
-    return log4rs::init_config(config).unwrap();
-}
\ No newline at end of file
+                .filter(Box::new(ThresholdFilter::new(LevelFilter::Error)))
+                .build("error_file", Box::new(error_file)),
+        );
+        root = root.appender("error_file");
+    }
+    Ok(config.build(root.build(LevelFilter::Trace))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use log::{Level, Log, Record};
+    use super::*;
+    use crate::test_server::test_dir;
+
+    // Log `lines` through a logger built from `config`, without installing
+    // it as the global one
+    fn log_lines(config: Config, lines: &[(Level, &str)]) {
+        let logger = log4rs::Logger::new(config);
+        for (level, message) in lines {
+            logger.log(&Record::builder().level(*level).args(format_args!("{}", message)).build());
+        }
+        Log::flush(&logger);
+    }
+
+    #[test]
+    fn log_file_rolls_over_by_size() {
+        let dir = test_dir("log-rotation");
+        let log_path = dir.join("download.log");
+        let rotation = LogRotation{max_size: 256, keep: 3};
+        let config = log_config(LevelFilter::Off, Some(log_path.clone()), LogFormat::Human, Some(rotation), None).unwrap();
+        let line = "a log line long enough to fill the log file quickly";
+        log_lines(config, &vec![(Level::Info, line); 50]);
+        // Rolled over once past the size, so by at most one line
+        for rolled in ["download.log.0", "download.log.1", "download.log.2"] {
+            let size = fs::metadata(dir.join(rolled)).unwrap().len();
+            assert!(size > 256 && size < 256 + 2 * line.len() as u64, "{} has {} bytes", rolled, size);
+        }
+        assert!(!dir.join("download.log.3").exists());
+    }
+
+    #[test]
+    fn unopenable_log_file_is_an_error() {
+        let dir = test_dir("log-unopenable");
+        // Log files can't go into a directory that is a file
+        let not_a_dir = dir.join("file");
+        fs::write(&not_a_dir, "").unwrap();
+        let log_path = not_a_dir.join("download.log");
+        let rotation = LogRotation{max_size: 256, keep: 3};
+        assert!(log_config(LevelFilter::Off, Some(log_path.clone()), LogFormat::Human, None, None).is_err());
+        assert!(log_config(LevelFilter::Off, Some(log_path.clone()), LogFormat::Human, Some(rotation), None).is_err());
+        assert!(log_config(LevelFilter::Off, None, LogFormat::Human, None, Some(log_path)).is_err());
+    }
+
+    #[test]
+    fn error_file_gets_only_errors() {
+        let dir = test_dir("log-errors");
+        let (log_path, error_path) = (dir.join("download.log"), dir.join("errors.log"));
+        let config = log_config(LevelFilter::Off, Some(log_path.clone()), LogFormat::Human, None, Some(error_path.clone())).unwrap();
+        log_lines(config, &[
+            (Level::Info, "starting"),
+            (Level::Error, "chunk failed"),
+            (Level::Warn, "retrying"),
+            (Level::Debug, "details"),
+            (Level::Error, "giving up"),
+        ]);
+        let errors = fs::read_to_string(&error_path).unwrap();
+        let lines: Vec<&str> = errors.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("ERROR") && lines[0].ends_with("chunk failed"));
+        assert!(lines[1].starts_with("ERROR") && lines[1].ends_with("giving up"));
+        // Everything still goes to the log file
+        assert_eq!(fs::read_to_string(&log_path).unwrap().lines().count(), 5);
+    }
+
+    #[test]
+    fn json_log_lines_parse() {
+        let dir = test_dir("log-json");
+        let log_path = dir.join("download.log");
+        let config = log_config(LevelFilter::Off, Some(log_path.clone()), LogFormat::Json, None, None).unwrap();
+        log_lines(config, &[(Level::Info, "starting"), (Level::Warn, "a \"quoted\"\nmessage"), (Level::Error, "failed")]);
+        let text = fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<serde_json::Value> = text.lines()
+            .map(|line| serde_json::from_str(line).unwrap_or_else(|err| panic!("{}: {}", err, line)))
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!((lines[0]["level"].as_str(), lines[0]["message"].as_str()), (Some("INFO"), Some("starting")));
+        assert_eq!(lines[1]["message"], "a \"quoted\"\nmessage");
+        assert_eq!(lines[2]["level"], "ERROR");
+        assert!(lines.iter().all(|line| line["time"].is_string()));
+        assert!("yaml".parse::<LogFormat>().unwrap_err().contains("expected human or json"));
+    }
+
+    #[test]
+    fn levels_are_colored_only_on_terminals() {
+        use log4rs::encode::writer::ansi::AnsiWriter;
+        // What an encoder writes to a terminal for a warning
+        fn encode(encoder: &dyn Encode) -> String {
+            let mut writer = AnsiWriter(Vec::new());
+            let record = Record::builder().level(Level::Warn).args(format_args!("retrying")).build();
+            encoder.encode(&mut writer, &record).unwrap();
+            String::from_utf8(writer.0).unwrap()
+        }
+        assert!(color_enabled(None, true));
+        assert!(color_enabled(Some(OsString::new()), true));
+        assert!(!color_enabled(Some(OsString::from("1")), true));
+        assert!(!color_enabled(None, false));
+
+        let colored = encode(&PatternEncoder::new(console_pattern(true)));
+        assert!(colored.contains("\x1b[") && colored.contains("WARN"), "{:?}", colored);
+        let plain = encode(&PatternEncoder::new(console_pattern(false)));
+        assert!(!plain.contains('\x1b') && plain.contains("WARN"), "{:?}", plain);
+        let file = encode(file_encoder(LogFormat::Human).as_ref());
+        assert!(!file.contains('\x1b'), "{:?}", file);
+    }
+}
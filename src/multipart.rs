@@ -0,0 +1,162 @@
+use std::io::{self, BufRead, BufReader, Read};
+use anyhow::{bail, Context, Result};
+use crate::http::parse_content_range;
+
+// Boundary of a `multipart/byteranges; boundary=<boundary>` Content-Type,
+// None for any other type
+pub fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (media_type, parameters) = content_type.split_once(';')?;
+    if !media_type.trim().eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    for parameter in parameters.split(';') {
+        let Some((name, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("boundary") {
+            return Some(value.trim().trim_matches('"').to_string()).filter(|boundary| !boundary.is_empty());
+        }
+    }
+    None
+}
+
+/// The parts of a `multipart/byteranges` response body, one after the
+/// other. Reads return the bytes of the current part, the range its
+/// Content-Range names.
+pub struct Multipart {
+    reader: BufReader<Box<dyn Read>>,
+    boundary: String,
+    // Bytes of the current part not read yet
+    remaining: u64,
+    // Set at the closing boundary
+    done: bool,
+}
+
+impl Multipart {
+    pub fn new(body: Box<dyn Read>, boundary: &str) -> Self {
+        Multipart{reader: BufReader::new(body), boundary: boundary.to_string(), remaining: 0, done: false}
+    }
+
+    /// Move on to the next part and return the bytes it holds, None after
+    /// the last part. What is left of the current part is skipped.
+    pub fn next_part(&mut self) -> Result<Option<(usize, usize)>> {
+        io::copy(&mut self.reader.by_ref().take(self.remaining), &mut io::sink())?;
+        self.remaining = 0;
+        if self.done {
+            return Ok(None);
+        }
+        // The line break ending the previous part, or a preamble, comes first
+        let delimiter = format!("--{}", self.boundary);
+        loop {
+            let line = self.read_line()?;
+            if line == delimiter {
+                break;
+            }
+            if line.strip_prefix(&delimiter) == Some("--") {
+                self.done = true;
+                return Ok(None);
+            }
+        }
+        let mut range = None;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-range") {
+                    range = parse_content_range(value);
+                }
+            }
+        }
+        let (start, end) = range
+            .filter(|(start, end)| start <= end)
+            .context("multipart part without a valid Content-Range")?;
+        self.remaining = (end - start + 1) as u64;
+        Ok(Some((start, end)))
+    }
+
+    // A line of boundaries and part headers, without the line break and
+    // the whitespace delimiters may be padded with
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            bail!("multipart body ended before its closing boundary");
+        }
+        Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+    }
+}
+
+impl Read for Multipart {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.reader.read(&mut buf[..len])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multipart(body: &str) -> Multipart {
+        Multipart::new(Box::new(io::Cursor::new(body.as_bytes().to_vec())), "SEP")
+    }
+
+    fn read_part(parts: &mut Multipart) -> String {
+        let mut part = String::new();
+        parts.read_to_string(&mut part).unwrap();
+        part
+    }
+
+    #[test]
+    fn boundary_of_byteranges_only() {
+        assert_eq!(multipart_boundary("multipart/byteranges; boundary=SEP").as_deref(), Some("SEP"));
+        assert_eq!(multipart_boundary("Multipart/ByteRanges; charset=x; Boundary=\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(multipart_boundary("multipart/byteranges; boundary=\"\""), None);
+        assert_eq!(multipart_boundary("multipart/mixed; boundary=SEP"), None);
+        assert_eq!(multipart_boundary("application/octet-stream"), None);
+    }
+
+    #[test]
+    fn reads_parts_in_order() {
+        let mut parts = multipart(concat!(
+            "preamble\r\n",
+            "--SEP\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-4/20\r\n\r\nhello\r\n",
+            "--SEP\r\nContent-Range: bytes 10-14/20\r\n\r\nworld\r\n",
+            "--SEP--\r\n",
+        ));
+        assert_eq!(parts.next_part().unwrap(), Some((0, 4)));
+        assert_eq!(read_part(&mut parts), "hello");
+        assert_eq!(parts.next_part().unwrap(), Some((10, 14)));
+        assert_eq!(read_part(&mut parts), "world");
+        assert_eq!(parts.next_part().unwrap(), None);
+        assert_eq!(parts.next_part().unwrap(), None);
+    }
+
+    #[test]
+    fn skips_unread_rest_of_part() {
+        let mut parts = multipart(concat!(
+            "\r\n--SEP\r\nContent-Range: bytes 0-9/20\r\n\r\n0123456789\r\n",
+            "--SEP\r\nContent-Range: bytes 15-19/20\r\n\r\nabcde\r\n--SEP--\r\n",
+        ));
+        parts.next_part().unwrap();
+        let mut start = [0; 3];
+        parts.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"012");
+        assert_eq!(parts.next_part().unwrap(), Some((15, 19)));
+        assert_eq!(read_part(&mut parts), "abcde");
+    }
+
+    #[test]
+    fn rejects_broken_bodies() {
+        let mut parts = multipart("--SEP\r\nContent-Type: text/plain\r\n\r\nhello\r\n--SEP--\r\n");
+        assert!(parts.next_part().is_err());
+        let mut parts = multipart("--SEP\r\nContent-Range: bytes 5-1/20\r\n\r\n");
+        assert!(parts.next_part().is_err());
+        let mut parts = multipart("--SEP\r\nContent-Range: bytes 0-4/20\r\n\r\nhello\r\n");
+        assert_eq!(parts.next_part().unwrap(), Some((0, 4)));
+        assert!(parts.next_part().is_err());
+    }
+}
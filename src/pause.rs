@@ -0,0 +1,39 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Condvar, Mutex};
+use crate::cancel::ABORT_POLL;
+
+/// Holds a running download without giving it up. Clones share the same
+/// state, so one can be handed to the downloader and another kept around to
+/// pause and resume it.
+#[derive(Debug, Clone, Default)]
+pub struct PauseHandle {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PauseHandle {
+    pub fn new() -> Self {
+        PauseHandle::default()
+    }
+
+    /// Stop network activity, transfers in flight stall until resumed
+    pub fn pause(&self) {
+        *self.state.0.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.state.0.lock().unwrap() = false;
+        self.state.1.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    // Block while paused, unless `abort` gets set meanwhile
+    pub(crate) fn wait_while_paused(&self, abort: &AtomicBool) {
+        let (paused, resumed) = &*self.state;
+        let mut paused = paused.lock().unwrap();
+        while *paused && !abort.load(Ordering::Relaxed) {
+            paused = resumed.wait_timeout(paused, ABORT_POLL).unwrap().0;
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use serde_json::json;
+use parallel_downloader::ProgressEvent;
+
+// How often progress is logged when there is no progress bar
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+// Throughput bookkeeping for the log lines
+struct LogState {
+    started: Instant,
+    // Bytes already there when the download started, e.g. when resuming
+    start_bytes: Option<usize>,
+    last_log: Instant,
+    last_bytes: usize,
+}
+
+impl LogState {
+    fn new() -> Self {
+        let now = Instant::now();
+        LogState {
+            started: now,
+            start_bytes: None,
+            last_log: now,
+            last_bytes: 0,
+        }
+    }
+
+    // Speed since the last log line, average speed and ETA once
+    // `bytes_downloaded` of `content_length` bytes are in at `now`
+    fn advance(&mut self, start_bytes: usize, bytes_downloaded: usize, content_length: usize, now: Instant) -> (f64, f64, Option<Duration>) {
+        let current = bytes_per_sec(bytes_downloaded - self.last_bytes, now - self.last_log);
+        let average = bytes_per_sec(bytes_downloaded - start_bytes, now - self.started);
+        let remaining = content_length.saturating_sub(bytes_downloaded);
+        self.last_log = now;
+        self.last_bytes = bytes_downloaded;
+        (current, average, eta(remaining, average))
+    }
+}
+
+fn bytes_per_sec(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+// Remaining bytes at the average speed so far, None before anything arrived
+fn eta(remaining: usize, average_speed: f64) -> Option<Duration> {
+    if average_speed <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(remaining as f64 / average_speed))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// How long a download took, as precise as is worth reading: 0.42s, 1.93s,
+// 2m 12.4s, 1h 05m 03s
+pub fn format_elapsed(elapsed: Duration) -> String {
+    // Rounded before splitting so 59.999s doesn't come out as 60.00s
+    let centis = (elapsed.as_secs_f64() * 100.0).round() as u64;
+    if centis < 60 * 100 {
+        return format!("{}.{:02}s", centis / 100, centis % 100);
+    }
+    let tenths = (elapsed.as_secs_f64() * 10.0).round() as u64;
+    if tenths < 3600 * 10 {
+        return format!("{}m {}.{}s", tenths / 600, tenths % 600 / 10, tenths % 10);
+    }
+    let secs = elapsed.as_secs_f64().round() as u64;
+    format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// Shows download progress on the console: a progress bar when attached to
+// a terminal, periodic log lines otherwise, or one JSON event per line on
+// stdout for other programs
+pub struct ProgressReporter {
+    bar: Option<ProgressBar>,
+    json: bool,
+    log_state: Mutex<LogState>,
+}
+
+impl ProgressReporter {
+    pub fn new(show_bar: bool) -> Self {
+        let bar = if show_bar {
+            let style = ProgressStyle::with_template(
+                "[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta} {msg}",
+            )
+            .unwrap()
+            .progress_chars("=> ");
+            Some(ProgressBar::new(0).with_style(style))
+        } else {
+            None
+        };
+        ProgressReporter {
+            bar,
+            json: false,
+            log_state: Mutex::new(LogState::new()),
+        }
+    }
+
+    pub fn json() -> Self {
+        ProgressReporter {
+            bar: None,
+            json: true,
+            log_state: Mutex::new(LogState::new()),
+        }
+    }
+
+    pub fn update(&self, event: ProgressEvent) {
+        if self.json {
+            let line = match event.chunk_id {
+                None => json!({
+                    "event": "start",
+                    "content_length": event.content_length,
+                    "total_chunks": event.total_chunks,
+                    "completed_chunks": event.completed_chunks,
+                    "bytes_downloaded": event.bytes_downloaded,
+                }),
+                Some(id) => json!({
+                    "event": "chunk_done",
+                    "id": id,
+                    "bytes": event.chunk_bytes,
+                    "completed_chunks": event.completed_chunks,
+                    "total_chunks": event.total_chunks,
+                    "bytes_downloaded": event.bytes_downloaded,
+                    "content_length": event.content_length,
+                }),
+            };
+            println!("{}", line);
+            return;
+        }
+        let done = event.completed_chunks == event.total_chunks;
+        match &self.bar {
+            Some(bar) => {
+                bar.set_length(event.content_length as u64);
+                bar.set_position(event.bytes_downloaded as u64);
+                bar.set_message(format!("{}/{} chunks", event.completed_chunks, event.total_chunks));
+                if done {
+                    bar.finish();
+                }
+            }
+            None => {
+                let mut state = self.log_state.lock().unwrap();
+                let start_bytes = *state.start_bytes.get_or_insert(event.bytes_downloaded);
+                if event.chunk_id.is_none() {
+                    state.last_bytes = event.bytes_downloaded;
+                    return;
+                }
+                if !done && state.last_log.elapsed() < LOG_INTERVAL {
+                    return;
+                }
+                let (current, average, eta) = state.advance(start_bytes, event.bytes_downloaded, event.content_length, Instant::now());
+                let eta = eta.map_or("unknown".to_string(), format_duration);
+                info!(
+                    "downloaded {}/{} chunks, {}/{} bytes, {:.2} MB/s (average {:.2} MB/s), ETA {}",
+                    event.completed_chunks, event.total_chunks, event.bytes_downloaded, event.content_length,
+                    current / 1e6, average / 1e6, eta,
+                );
+            }
+        }
+    }
+
+    // Leave the bar where it stopped if the download didn't complete
+    pub fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            if !bar.is_finished() {
+                bar.abandon();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speeds_and_eta_from_bytes_over_time() {
+        let mut state = LogState::new();
+        let started = state.started;
+        let secs = |secs| started + Duration::from_secs(secs);
+        // 10 MB in the first 5 seconds, 30 MB in the next 5
+        let (current, average, time_left) = state.advance(0, 10_000_000, 100_000_000, secs(5));
+        assert_eq!((current, average), (2e6, 2e6));
+        assert_eq!(time_left, Some(Duration::from_secs(45)));
+        let (current, average, time_left) = state.advance(0, 40_000_000, 100_000_000, secs(10));
+        assert_eq!((current, average), (6e6, 4e6));
+        assert_eq!(time_left, Some(Duration::from_secs(15)));
+        // Bytes from before a resume don't count towards the speed
+        let mut state = LogState::new();
+        let (current, average, _) = state.advance(50_000_000, 60_000_000, 100_000_000, state.started + Duration::from_secs(2));
+        assert_eq!((current, average), (30e6, 5e6));
+        assert_eq!(eta(100, 0.0), None);
+    }
+
+    #[test]
+    fn durations_read_well() {
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1:02:05");
+        assert_eq!(format_elapsed(Duration::from_millis(420)), "0.42s");
+        assert_eq!(format_elapsed(Duration::from_millis(59_999)), "1m 0.0s");
+        assert_eq!(format_elapsed(Duration::from_millis(132_400)), "2m 12.4s");
+        assert_eq!(format_elapsed(Duration::from_secs(3903)), "1h 05m 03s");
+    }
+
+    #[test]
+    fn elapsed_times_keep_sub_second_precision() {
+        // Sub-second
+        assert_eq!(format_elapsed(Duration::ZERO), "0.00s");
+        assert_eq!(format_elapsed(Duration::from_millis(7)), "0.01s");
+        // Seconds, not truncated to whole ones
+        assert_eq!(format_elapsed(Duration::from_millis(1930)), "1.93s");
+        assert_eq!(format_elapsed(Duration::from_millis(59_994)), "59.99s");
+        // Minutes
+        assert_eq!(format_elapsed(Duration::from_secs(60)), "1m 0.0s");
+        assert_eq!(format_elapsed(Duration::from_millis(3_599_940)), "59m 59.9s");
+        assert_eq!(format_elapsed(Duration::from_millis(3_599_960)), "1h 00m 00s");
+    }
+}
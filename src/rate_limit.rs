@@ -0,0 +1,116 @@
+use std::{
+    io::{self, Read},
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use crate::cancel::ABORT_POLL;
+
+// Token bucket shared by all workers so the aggregate rate is bounded.
+// It starts empty and holds at most one read worth of bytes, so there is
+// no burst above the rate, not even at the start.
+pub struct RateLimiter {
+    rate: f64,
+    // Available bytes, negative when readers are waiting for their share
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: usize) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        RateLimiter {
+            rate,
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    // Largest read worth doing at once, so a single read never has to wait
+    // much longer than a tenth of a second for its tokens
+    fn max_read(&self) -> usize {
+        (self.rate / 10.0).max(1.0) as usize
+    }
+
+    // Take `bytes` tokens and wait until they are paid for
+    fn consume(&self, bytes: usize, abort: &AtomicBool) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.max_read() as f64);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-*tokens / self.rate)
+        };
+        let deadline = Instant::now() + wait;
+        while !abort.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep((deadline - now).min(ABORT_POLL));
+        }
+    }
+}
+
+// Reads no faster than the shared limiter allows, if there is one
+pub struct RateLimitedReader<'a, R> {
+    pub inner: R,
+    pub limiter: Option<&'a RateLimiter>,
+    pub abort: &'a AtomicBool,
+}
+
+impl<R: Read> Read for RateLimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(limiter) = self.limiter else {
+            return self.inner.read(buf);
+        };
+        let len = buf.len().min(limiter.max_read());
+        let n = self.inner.read(&mut buf[..len])?;
+        limiter.consume(n, self.abort);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_without_a_burst() {
+        let limiter = RateLimiter::new(10_000);
+        let abort = AtomicBool::new(false);
+        let started = Instant::now();
+        // A second's worth in reads of the largest size
+        for _ in 0..10 {
+            limiter.consume(limiter.max_read(), &abort);
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(950), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn idle_time_saves_up_one_read() {
+        let limiter = RateLimiter::new(10_000);
+        let abort = AtomicBool::new(false);
+        thread::sleep(Duration::from_millis(500));
+        let started = Instant::now();
+        limiter.consume(limiter.max_read(), &abort);
+        assert!(started.elapsed() < Duration::from_millis(50));
+        // Half a second idle bought a tenth of a second, not more
+        limiter.consume(limiter.max_read(), &abort);
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn abort_stops_waiting() {
+        let limiter = RateLimiter::new(1);
+        let abort = AtomicBool::new(true);
+        let started = Instant::now();
+        limiter.consume(1000, &abort);
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
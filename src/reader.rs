@@ -0,0 +1,92 @@
+use std::{
+    io::{self, Read, Write},
+    sync::mpsc::{Receiver, SyncSender},
+    thread::JoinHandle,
+};
+use crate::{CancellationToken, DownloadError, DownloadStats};
+
+// Hands the output of a download over to its DownloadReader, blocking
+// while the reader is behind
+pub struct ChannelWriter {
+    pub sender: SyncSender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender.send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "download reader was dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The file of a download running in the background, in order and as it
+/// arrives, created through [`Downloader::reader`](crate::Downloader::reader).
+/// Reads block until the next bytes are downloaded, a failed download shows
+/// up as a read error. Dropping the reader cancels the download.
+pub struct DownloadReader {
+    receiver: Receiver<Vec<u8>>,
+    data: Vec<u8>,
+    pos: usize,
+    // Taken once the download is over
+    download: Option<JoinHandle<Result<DownloadStats, DownloadError>>>,
+    cancel: CancellationToken,
+    stats: Option<DownloadStats>,
+}
+
+impl DownloadReader {
+    pub(crate) fn new(
+        receiver: Receiver<Vec<u8>>,
+        download: JoinHandle<Result<DownloadStats, DownloadError>>,
+        cancel: CancellationToken,
+    ) -> Self {
+        DownloadReader{receiver, data: Vec::new(), pos: 0, download: Some(download), cancel, stats: None}
+    }
+
+    /// Summary of the download, once the whole file was read
+    pub fn stats(&self) -> Option<&DownloadStats> {
+        self.stats.as_ref()
+    }
+}
+
+impl Read for DownloadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.data.len() {
+            match self.receiver.recv() {
+                Ok(data) => {
+                    self.data = data;
+                    self.pos = 0;
+                }
+                // The download is over once nothing can send anymore
+                Err(_) => {
+                    let Some(download) = self.download.take() else {
+                        return Ok(0);
+                    };
+                    match download.join() {
+                        Ok(Ok(stats)) => {
+                            self.stats = Some(stats);
+                            return Ok(0);
+                        }
+                        Ok(Err(err)) => return Err(io::Error::other(err)),
+                        Err(_) => return Err(io::Error::other("download thread panicked")),
+                    }
+                }
+            }
+        }
+        let len = buf.len().min(self.data.len() - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Drop for DownloadReader {
+    fn drop(&mut self) {
+        if self.download.is_some() {
+            self.cancel.cancel();
+        }
+    }
+}
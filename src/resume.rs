@@ -0,0 +1,251 @@
+use std::{fs::{self, File}, io::{self, Write}};
+use log::debug;
+
+// Start of every progress file, bumped when the layout changes
+const MAGIC: &[u8; 8] = b"PDRESUM3";
+
+// One bit per chunk, set once the chunk is safely on disk
+#[derive(Debug, Default)]
+pub struct ChunkBitmap {
+    bits: Vec<u8>,
+}
+
+impl ChunkBitmap {
+    pub fn insert(&mut self, id: usize) {
+        let byte = id / 8;
+        if byte >= self.bits.len() {
+            self.bits.resize(byte + 1, 0);
+        }
+        self.bits[byte] |= 1 << (id % 8);
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        if let Some(byte) = self.bits.get_mut(id / 8) {
+            *byte &= !(1 << (id % 8));
+        }
+    }
+
+    pub fn contains(&self, id: &usize) -> bool {
+        self.bits.get(id / 8).is_some_and(|byte| byte & (1 << (id % 8)) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+}
+
+// Reads the little endian fields of a progress file in order
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+}
+
+// Sidecar state kept next to the output file while downloading, so an
+// interrupted download can skip the chunks it already completed. Stored as
+// the magic, remote offset of the first byte, content length, chunk size,
+// validator of the remote file (length prefixed, empty for none), the CRC32
+// of every chunk (count prefixed) and the chunk bitmap.
+#[derive(Debug)]
+pub struct ResumeState {
+    // Where the downloaded bytes start in the remote file, see --range
+    pub range_offset: usize,
+    pub content_length: usize,
+    pub chunk_size: usize,
+    pub validator: Option<String>,
+    pub completed: ChunkBitmap,
+    // By chunk id, only meaningful for completed chunks
+    checksums: Vec<u32>,
+}
+
+impl ResumeState {
+    pub fn new(range_offset: usize, content_length: usize, chunk_size: usize, validator: Option<String>) -> Self {
+        ResumeState {
+            range_offset,
+            content_length,
+            chunk_size,
+            validator,
+            completed: ChunkBitmap::default(),
+            checksums: Vec::new(),
+        }
+    }
+
+    // Mark a chunk completed with the CRC32 of its bytes
+    pub fn complete(&mut self, id: usize, checksum: u32) {
+        if id >= self.checksums.len() {
+            self.checksums.resize(id + 1, 0);
+        }
+        self.checksums[id] = checksum;
+        self.completed.insert(id);
+    }
+
+    // CRC32 of a completed chunk
+    pub fn checksum(&self, id: usize) -> Option<u32> {
+        if !self.completed.contains(&id) {
+            return None;
+        }
+        self.checksums.get(id).copied()
+    }
+
+    pub fn path(file_name: &str) -> String {
+        format!("{}.progress", file_name)
+    }
+
+    pub fn load(path: &str) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        let state = Self::decode(&data);
+        if state.is_none() {
+            debug!("ignoring unreadable progress file {}", path);
+        }
+        state
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let mut fields = Fields{data};
+        if fields.bytes(MAGIC.len())? != MAGIC {
+            return None;
+        }
+        let range_offset = fields.u64()?.try_into().ok()?;
+        let content_length = fields.u64()?.try_into().ok()?;
+        let chunk_size = fields.u64()?.try_into().ok()?;
+        let validator_length = fields.u64()?.try_into().ok()?;
+        let validator = String::from_utf8(fields.bytes(validator_length)?.to_vec()).ok()?;
+        let num_checksums = fields.u64()?;
+        let checksums = (0..num_checksums).map(|_| fields.u32()).collect::<Option<_>>()?;
+        Some(ResumeState {
+            range_offset,
+            content_length,
+            chunk_size,
+            validator: Some(validator).filter(|validator| !validator.is_empty()),
+            completed: ChunkBitmap{bits: fields.data.to_vec()},
+            checksums,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let validator = self.validator.as_deref().unwrap_or_default().as_bytes();
+        let mut data = Vec::with_capacity(
+            MAGIC.len() + 40 + validator.len() + 4 * self.checksums.len() + self.completed.bits.len(),
+        );
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&(self.range_offset as u64).to_le_bytes());
+        data.extend_from_slice(&(self.content_length as u64).to_le_bytes());
+        data.extend_from_slice(&(self.chunk_size as u64).to_le_bytes());
+        data.extend_from_slice(&(validator.len() as u64).to_le_bytes());
+        data.extend_from_slice(validator);
+        data.extend_from_slice(&(self.checksums.len() as u64).to_le_bytes());
+        for checksum in &self.checksums {
+            data.extend_from_slice(&checksum.to_le_bytes());
+        }
+        data.extend_from_slice(&self.completed.bits);
+        data
+    }
+
+    // Write to a temporary file first so a crash never leaves a torn state,
+    // synced if asked to so that the rename can't overtake the data
+    pub fn save(&self, path: &str, sync: bool) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&self.encode())?;
+        if sync {
+            file.sync_data()?;
+        }
+        fs::rename(tmp_path, path)
+    }
+
+    // A previous state is only usable for the same bytes of the same remote
+    // file chunked the same way
+    pub fn matches(&self, other: &ResumeState) -> bool {
+        self.range_offset == other.range_offset
+            && self.content_length == other.content_length
+            && self.chunk_size == other.chunk_size
+            && self.validator == other.validator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::test_dir;
+
+    fn state() -> ResumeState {
+        let mut state = ResumeState::new(1000, 5000, 1024, Some("\"etag\"".to_string()));
+        state.complete(0, 0xdeadbeef);
+        state.complete(3, 7);
+        state.complete(12, 0);
+        state
+    }
+
+    #[test]
+    fn bitmap_tracks_chunks() {
+        let mut bitmap = ChunkBitmap::default();
+        for id in [0, 7, 8, 100] {
+            bitmap.insert(id);
+        }
+        bitmap.insert(7);
+        assert_eq!(bitmap.len(), 4);
+        assert!(bitmap.contains(&8) && bitmap.contains(&100));
+        assert!(!bitmap.contains(&1) && !bitmap.contains(&1000));
+        bitmap.remove(8);
+        bitmap.remove(1000);
+        assert!(!bitmap.contains(&8));
+        assert_eq!(bitmap.len(), 3);
+    }
+
+    #[test]
+    fn round_trips_through_the_progress_file() {
+        let path = test_dir("resume-round-trip").join("file.progress");
+        let path = path.to_str().unwrap();
+        state().save(path, true).unwrap();
+        let loaded = ResumeState::load(path).unwrap();
+        assert!(loaded.matches(&state()));
+        assert_eq!(loaded.range_offset, 1000);
+        assert_eq!(loaded.completed.len(), 3);
+        assert_eq!(loaded.checksum(0), Some(0xdeadbeef));
+        assert_eq!(loaded.checksum(3), Some(7));
+        assert_eq!(loaded.checksum(12), Some(0));
+        assert_eq!(loaded.checksum(1), None);
+        assert_eq!(loaded.checksum(13), None);
+    }
+
+    #[test]
+    fn matches_only_the_same_download() {
+        let state = state();
+        assert!(state.matches(&ResumeState::new(1000, 5000, 1024, Some("\"etag\"".to_string()))));
+        assert!(!state.matches(&ResumeState::new(0, 5000, 1024, Some("\"etag\"".to_string()))));
+        assert!(!state.matches(&ResumeState::new(1000, 5001, 1024, Some("\"etag\"".to_string()))));
+        assert!(!state.matches(&ResumeState::new(1000, 5000, 2048, Some("\"etag\"".to_string()))));
+        assert!(!state.matches(&ResumeState::new(1000, 5000, 1024, Some("\"other\"".to_string()))));
+        assert!(!state.matches(&ResumeState::new(1000, 5000, 1024, None)));
+    }
+
+    #[test]
+    fn rejects_other_and_torn_files() {
+        let data = state().encode();
+        assert!(ResumeState::decode(&data).is_some());
+        let mut old = data.clone();
+        old[..MAGIC.len()].copy_from_slice(b"PDRESUM2");
+        assert!(ResumeState::decode(&old).is_none());
+        // Cut off in the checksums
+        assert!(ResumeState::decode(&data[..data.len() - 8]).is_none());
+        assert!(ResumeState::decode(&data[..MAGIC.len() + 4]).is_none());
+        assert!(ResumeState::load("/nonexistent/file.progress").is_none());
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, Condvar, Mutex};
+use crate::cancel::ABORT_POLL;
+
+// Counting semaphore shared by all workers to cap the requests in flight,
+// whatever the number of workers
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore{permits: Mutex::new(permits.max(1)), released: Condvar::new()}
+    }
+
+    // Block until a permit is free, or give up with None once `abort` is set
+    pub fn acquire(&self, abort: &AtomicBool) -> Option<Permit<'_>> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            if abort.load(Ordering::Relaxed) {
+                return None;
+            }
+            permits = self.released.wait_timeout(permits, ABORT_POLL).unwrap().0;
+        }
+        *permits -= 1;
+        Some(Permit{semaphore: self})
+    }
+}
+
+// Gives its permit back when dropped
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
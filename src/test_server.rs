@@ -0,0 +1,373 @@
+// A small HTTP/1.1 server on a local port for tests. Requests go to a
+// handler deciding the response, serve_file() answers them like a server
+// with byte range support would.
+//
+// Also built into the binary's tests and the integration tests, so it only
+// uses std, rustls and log.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+// Separates the parts of multipart/byteranges responses
+pub const BOUNDARY: &str = "TEST_BOUNDARY";
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The single `bytes=<start>-<end>` range asked for, if that is what
+    // the Range header holds
+    pub fn range(&self) -> Option<(usize, usize)> {
+        match parse_ranges(self.header("range")?, usize::MAX)?[..] {
+            [range] => Some(range),
+            _ => None,
+        }
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Send the body in pieces of this size with a pause after each one,
+    // for slow and stalling servers
+    pub pace: Option<(usize, Duration)>,
+    // Leave out Content-Length and end the body by closing the connection
+    pub close_delimited: bool,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response{status, headers: Vec::new(), body: Vec::new(), pace: None, close_delimited: false}
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn paced(mut self, piece: usize, pause: Duration) -> Self {
+        self.pace = Some((piece.max(1), pause));
+        self
+    }
+
+    pub fn close_delimited(mut self) -> Self {
+        self.close_delimited = true;
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+pub struct TestServer {
+    addr: SocketAddr,
+    scheme: &'static str,
+    requests: Arc<Mutex<Vec<Request>>>,
+    // Most requests handled at the same time so far
+    max_in_flight: Arc<AtomicUsize>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl TestServer {
+    pub fn new(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> Self {
+        TestServer::start(handler, None)
+    }
+
+    // Serves over TLS with testdata/localhost.der, a certificate for
+    // 127.0.0.1 and localhost signed by testdata/test-ca.pem
+    pub fn https(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> Self {
+        let testdata = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let certificate = CertificateDer::from(fs::read(testdata.join("localhost.der")).unwrap());
+        let key = PrivatePkcs8KeyDer::from(fs::read(testdata.join("localhost.key.der")).unwrap());
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate], PrivateKeyDer::Pkcs8(key))
+            .expect("invalid test server certificate");
+        TestServer::start(handler, Some(Arc::new(config)))
+    }
+
+    fn start(
+        handler: impl Fn(&Request) -> Response + Send + Sync + 'static,
+        tls: Option<Arc<ServerConfig>>,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind test server");
+        let addr = listener.local_addr().unwrap();
+        let handler: Arc<Handler> = Arc::new(handler);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let server = TestServer{
+            addr,
+            scheme: if tls.is_some() { "https" } else { "http" },
+            requests: requests.clone(),
+            max_in_flight: max_in_flight.clone(),
+            connections: connections.clone(),
+        };
+        // Runs until the test process exits
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                connections.fetch_add(1, Ordering::SeqCst);
+                let (handler, requests) = (handler.clone(), requests.clone());
+                let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+                let tls = tls.clone();
+                thread::spawn(move || {
+                    // Heads and bodies go out in separate writes, which
+                    // Nagle's algorithm would hold back until the client
+                    // acknowledges the head
+                    let _ = stream.set_nodelay(true);
+                    let Some(config) = tls else {
+                        serve_connection(stream, handler.as_ref(), &requests, &in_flight, &max_in_flight);
+                        return;
+                    };
+                    let Ok(connection) = ServerConnection::new(config) else {
+                        return;
+                    };
+                    let mut stream = StreamOwned::new(connection, stream);
+                    serve_connection(&mut stream, handler.as_ref(), &requests, &in_flight, &max_in_flight);
+                    stream.conn.send_close_notify();
+                    let _ = stream.flush();
+                });
+            }
+        });
+        server
+    }
+
+    // Serves `body` at every path, with byte ranges
+    pub fn serving(body: Vec<u8>) -> Self {
+        TestServer::new(move |request| serve_file(request, &body, None))
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.scheme, self.addr, path)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Every request received so far, in order
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    // Connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+// Answer the requests coming in over one connection until it is closed
+fn serve_connection(
+    stream: impl Read + Write,
+    handler: &Handler,
+    requests: &Mutex<Vec<Request>>,
+    in_flight: &AtomicUsize,
+    max_in_flight: &AtomicUsize,
+) {
+    // Responses are written past the buffer, straight to the stream
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = read_request(&mut reader) {
+        requests.lock().unwrap().push(request.clone());
+        let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        max_in_flight.fetch_max(current, Ordering::SeqCst);
+        let response = handler(&request);
+        let written = write_response(reader.get_mut(), &request, &response);
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+        if written.is_err() || response.close_delimited {
+            return;
+        }
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok().filter(|read| *read > 0)?;
+    let mut words = line.split_whitespace();
+    let (method, path) = (words.next()?.to_string(), words.next()?.to_string());
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok().filter(|read| *read > 0)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Some(Request{method, path, headers})
+}
+
+fn write_response(stream: &mut impl Write, request: &Request, response: &Response) -> std::io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} Test\r\n", response.status);
+    for (name, value) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    let has_length = response.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-length"));
+    if response.close_delimited {
+        head.push_str("Connection: close\r\n");
+    } else if !has_length {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    // A HEAD response tells the length of the body it leaves out
+    if request.method == "HEAD" || response.status == 304 {
+        return stream.flush();
+    }
+    match response.pace {
+        Some((piece, pause)) => {
+            for piece in response.body.chunks(piece) {
+                stream.write_all(piece)?;
+                stream.flush()?;
+                thread::sleep(pause);
+            }
+        }
+        None => stream.write_all(&response.body)?,
+    }
+    stream.flush()
+}
+
+// Inclusive ranges of a `bytes=<start>-<end>,...` Range value into a file
+// of `length` bytes, None when it doesn't parse. Ends past the file are
+// clamped to it.
+fn parse_ranges(value: &str, length: usize) -> Option<Vec<(usize, usize)>> {
+    let mut ranges = Vec::new();
+    for range in value.trim().strip_prefix("bytes=")?.split(',') {
+        let (start, end) = range.trim().split_once('-')?;
+        let start: usize = start.trim().parse().ok()?;
+        let end = match end.trim() {
+            "" => length.saturating_sub(1),
+            end => end.parse::<usize>().ok()?.min(length.saturating_sub(1)),
+        };
+        ranges.push((start, end));
+    }
+    Some(ranges)
+}
+
+// Answer `request` for a file holding `body` the way a server serving byte
+// ranges does: ranges and multiple ranges get partial content, an If-Range
+// other than `etag` gets the whole file, ranges past the end a 416
+pub fn serve_file(request: &Request, body: &[u8], etag: Option<&str>) -> Response {
+    let mut response = Response::new(200).header("Accept-Ranges", "bytes");
+    if let Some(etag) = etag {
+        response = response.header("ETag", etag);
+    }
+    let changed = request.header("if-range").is_some_and(|validator| Some(validator) != etag);
+    let ranges = request.header("range")
+        .filter(|_| !changed)
+        .and_then(|value| parse_ranges(value, body.len()));
+    let Some(ranges) = ranges else {
+        return response.body(body);
+    };
+    if ranges.iter().any(|(start, end)| *start >= body.len() || start > end) {
+        response.status = 416;
+        return response.header("Content-Range", &format!("bytes */{}", body.len()));
+    }
+    response.status = 206;
+    if let [(start, end)] = ranges[..] {
+        let content_range = format!("bytes {}-{}/{}", start, end, body.len());
+        return response.header("Content-Range", &content_range).body(&body[start..=end]);
+    }
+    let mut parts = Vec::new();
+    for (start, end) in ranges {
+        parts.extend_from_slice(format!("\r\n--{}\r\n", BOUNDARY).as_bytes());
+        parts.extend_from_slice(b"Content-Type: application/octet-stream\r\n");
+        parts.extend_from_slice(format!("Content-Range: bytes {}-{}/{}\r\n\r\n", start, end, body.len()).as_bytes());
+        parts.extend_from_slice(&body[start..=end]);
+    }
+    parts.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+    let content_type = format!("multipart/byteranges; boundary={}", BOUNDARY);
+    response.header("Content-Type", &content_type).body(parts)
+}
+
+// Bytes without a short period, so a chunk in the wrong place shows
+pub fn test_data(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+// An empty directory of its own for a test
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("parallel-downloader-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Lines logged by threads whose name starts with `logged-`, with the
+// thread name
+struct ThreadLogger {
+    lines: Mutex<Vec<(String, String)>>,
+}
+
+impl log::Log for ThreadLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Some(name) = thread::current().name().filter(|name| name.starts_with("logged-")) {
+            self.lines.lock().unwrap().push((name.to_string(), record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static THREAD_LOGGER: ThreadLogger = ThreadLogger{lines: Mutex::new(Vec::new())};
+
+// Start recording the log lines of threads named `logged-*`. The first
+// test calling it installs the logger for the whole test process.
+pub fn capture_thread_logs() {
+    if log::set_logger(&THREAD_LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Debug);
+    }
+}
+
+// Lines logged so far by the workers of a download with the thread name
+// prefix `prefix`, named `<prefix>-<id>`
+pub fn worker_logs(prefix: &str) -> Vec<(String, String)> {
+    let lines = THREAD_LOGGER.lines.lock().unwrap();
+    lines.iter()
+        .filter(|(name, _)| name.strip_prefix(prefix)
+            .and_then(|id| id.strip_prefix('-'))
+            .is_some_and(|id| id.parse::<usize>().is_ok()))
+        .cloned()
+        .collect()
+}
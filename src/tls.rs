@@ -0,0 +1,268 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned,
+};
+use crate::ip_version::IpVersion;
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+// Certificates in `data`, either PEM with any number of certificates or a
+// single DER encoded one
+fn parse_certificates(data: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return Ok(vec![CertificateDer::from(data.to_vec())]);
+    };
+    if !text.contains(PEM_BEGIN) {
+        return Ok(vec![CertificateDer::from(data.to_vec())]);
+    }
+    let mut certificates = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        let body = &rest[start + PEM_BEGIN.len()..];
+        let Some(end) = body.find(PEM_END) else {
+            bail!("unterminated certificate");
+        };
+        let encoded: String = body[..end].chars().filter(|c| !c.is_whitespace()).collect();
+        let der = BASE64.decode(encoded).context("invalid base64 in certificate")?;
+        certificates.push(CertificateDer::from(der));
+        rest = &body[end + PEM_END.len()..];
+    }
+    Ok(certificates)
+}
+
+fn web_roots() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+// TLS config trusting the bundled web roots, what ureq uses by default
+pub fn default_client_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_root_certificates(web_roots())
+        .with_no_client_auth()
+}
+
+// TLS config trusting the bundled web roots plus the certificates in
+// `ca_cert`
+pub fn client_config(ca_cert: &Path) -> Result<ClientConfig> {
+    let mut roots = web_roots();
+    let data = fs::read(ca_cert)
+        .with_context(|| format!("failed to read {}", ca_cert.display()))?;
+    for certificate in parse_certificates(&data).with_context(|| format!("failed to parse {}", ca_cert.display()))? {
+        roots.add(certificate)
+            .with_context(|| format!("invalid CA certificate in {}", ca_cert.display()))?;
+    }
+    Ok(ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+// A connection of our own rather than ureq's, TLS wrapped for secure urls
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    // Take in `received`, read from a clone of the socket by a thread of
+    // its own, adding what it decrypts to `plaintext`. The stream stays
+    // free for writing while that thread waits.
+    pub fn receive(&mut self, received: &[u8], plaintext: &mut Vec<u8>) -> io::Result<()> {
+        let Stream::Tls(stream) = self else {
+            plaintext.extend_from_slice(received);
+            return Ok(());
+        };
+        let mut received = received;
+        while !received.is_empty() {
+            stream.conn.read_tls(&mut received)?;
+            let state = stream.conn.process_new_packets()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let start = plaintext.len();
+            plaintext.resize(start + state.plaintext_bytes_to_read(), 0);
+            stream.conn.reader().read_exact(&mut plaintext[start..])?;
+        }
+        // Key updates and alerts in reply
+        while stream.conn.wants_write() {
+            stream.conn.write_tls(&mut stream.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            // Plenty of servers close connections without a TLS
+            // close_notify, lengths still catch truncation
+            Stream::Tls(stream) => match stream.read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                result => result,
+            },
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// TCP connection to the first address of `host` that takes one, those of
+// the preferred IP version first
+pub fn connect(
+    host: &str,
+    port: u16,
+    ip_version: IpVersion,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    let mut last_err = None;
+    for addr in ip_version.resolve((host, port)).with_context(|| format!("failed to resolve {}", host))? {
+        let stream = match connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match stream {
+            Ok(stream) => {
+                stream.set_read_timeout(read_timeout)?;
+                return Ok(stream);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.map_or_else(|| anyhow!("no address for {}", host), anyhow::Error::from))
+        .with_context(|| format!("failed to connect to {}:{}", host, port))
+}
+
+// Start TLS on `stream` to `host`, the handshake happens on first use
+pub fn wrap(stream: TcpStream, host: &str, config: Arc<ClientConfig>) -> Result<Stream> {
+    let name = ServerName::try_from(host.to_string())
+        .with_context(|| format!("invalid TLS server name {}", host))?;
+    let connection = ClientConnection::new(config, name)?;
+    Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+}
+
+// Accepts any certificate for any name. Handshake signatures are still
+// checked, they only prove the server holds the key of the certificate it
+// sent.
+#[derive(Debug)]
+struct NoVerification {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// TLS config that doesn't verify server certificates at all
+pub fn insecure_client_config() -> ClientConfig {
+    let verifier = NoVerification {
+        algorithms: ring::default_provider().signature_verification_algorithms,
+    };
+    ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    #[test]
+    fn certificates_parse_from_pem_and_der() {
+        let der = fs::read(testdata("test-ca.der")).unwrap();
+        let pem = fs::read(testdata("test-ca.pem")).unwrap();
+        assert_eq!(parse_certificates(&pem).unwrap(), vec![CertificateDer::from(der.clone())]);
+        assert_eq!(parse_certificates(&der).unwrap(), vec![CertificateDer::from(der.clone())]);
+        let mut bundle = pem.clone();
+        bundle.extend_from_slice(&pem);
+        assert_eq!(parse_certificates(&bundle).unwrap().len(), 2);
+
+        let text = String::from_utf8(pem).unwrap();
+        let unterminated = text.replace(PEM_END, "");
+        assert!(parse_certificates(unterminated.as_bytes()).is_err());
+        let bad_base64 = text.replacen("\n", "\n!", 1);
+        assert!(parse_certificates(bad_base64.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn ca_certificates_are_added_to_the_roots() {
+        for name in ["test-ca.pem", "test-ca.der"] {
+            client_config(&testdata(name)).unwrap();
+        }
+        let err = client_config(&testdata("missing.pem")).unwrap_err();
+        assert!(format!("{:#}", err).contains("failed to read"), "{:#}", err);
+        let err = client_config(&testdata("sample.zst")).unwrap_err();
+        assert!(format!("{:#}", err).contains("invalid CA certificate"), "{:#}", err);
+    }
+}
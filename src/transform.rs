@@ -0,0 +1,96 @@
+use std::{fmt, io::Read};
+use flate2::read::MultiGzDecoder;
+use crate::zstd::ZstdDecoder;
+
+/// Decompression applied to the file on its way to the output, for files
+/// that are compressed themselves rather than sent with a Content-Encoding.
+/// Offsets into the compressed file don't map to the output, so a
+/// transformed download runs in a single stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    /// Decompress a gzip file, e.g. `.tar.gz`
+    Gunzip,
+    /// Decompress a zstd file, e.g. `.tar.zst`
+    Unzstd,
+}
+
+impl Transform {
+    // What `reader` reads, decompressed
+    pub(crate) fn apply<'a>(self, reader: impl Read + 'a) -> Box<dyn Read + 'a> {
+        match self {
+            // Members concatenated into one file decompress one after the other
+            Transform::Gunzip => Box::new(MultiGzDecoder::new(reader)),
+            Transform::Unzstd => Box::new(ZstdDecoder::new(reader)),
+        }
+    }
+
+    // The name a file of this kind decompresses to, e.g. `data.csv` for
+    // `data.csv.gz`. Names without one of its extensions are kept.
+    pub(crate) fn decompressed_name(self, name: &str) -> String {
+        let (extensions, tarball): (&[&str], &str) = match self {
+            Transform::Gunzip => (&[".gz", ".gzip"], ".tgz"),
+            Transform::Unzstd => (&[".zst", ".zstd"], ".tzst"),
+        };
+        if let Some(stem) = name.strip_suffix(tarball).filter(|stem| !stem.is_empty()) {
+            return format!("{}.tar", stem);
+        }
+        for extension in extensions {
+            if let Some(stem) = name.strip_suffix(extension).filter(|stem| !stem.is_empty()) {
+                return stem.to_string();
+            }
+        }
+        name.to_string()
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transform::Gunzip => write!(f, "gzip"),
+            Transform::Unzstd => write!(f, "zstd"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use flate2::{write::GzEncoder, Compression};
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn apply(transform: Transform, data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        transform.apply(data).read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn gunzips_every_member() {
+        let data = crate::test_server::test_data(100_000);
+        assert_eq!(apply(Transform::Gunzip, &gzip(&data)), data);
+        let members = [gzip(b"first, "), gzip(b"second")].concat();
+        assert_eq!(apply(Transform::Gunzip, &members), b"first, second");
+    }
+
+    #[test]
+    fn unzstds() {
+        let decoded = apply(Transform::Unzstd, include_bytes!("../testdata/sample.zst"));
+        assert_eq!(decoded.len(), 200 * 1024);
+        assert!(decoded.starts_with(b"#"));
+    }
+
+    #[test]
+    fn names_decompressed_files() {
+        assert_eq!(Transform::Gunzip.decompressed_name("data.csv.gz"), "data.csv");
+        assert_eq!(Transform::Gunzip.decompressed_name("src.tgz"), "src.tar");
+        assert_eq!(Transform::Gunzip.decompressed_name(".gz"), ".gz");
+        assert_eq!(Transform::Unzstd.decompressed_name("src.tar.zst"), "src.tar");
+        assert_eq!(Transform::Unzstd.decompressed_name("data.csv.gz"), "data.csv.gz");
+    }
+}
@@ -0,0 +1,97 @@
+use std::{fmt, io::Read};
+use crate::{error::DownloadError, multipart::Multipart};
+
+/// What the server reports about a file before downloading it
+#[derive(Debug, Clone, Default)]
+pub struct RemoteInfo {
+    /// Where the url finally led after following redirects, the url itself
+    /// when there were none
+    pub url: String,
+    /// None when the server doesn't say, the body then ends with the connection
+    pub content_length: Option<usize>,
+    /// Whether [`Transport::fetch_range`] can be used
+    pub accept_ranges: bool,
+    /// Changes whenever the file does, like a strong ETag or a Last-Modified
+    /// date. Used to resume only from the same version of the file.
+    pub validator: Option<String>,
+    /// ETag header as sent, weak ones included
+    pub etag: Option<String>,
+    /// Last-Modified header as sent
+    pub last_modified: Option<String>,
+    /// Content-Disposition header the file name may be taken from
+    pub content_disposition: Option<String>,
+    /// Content encoding of the body, e.g. `gzip`, None for identity
+    pub content_encoding: Option<String>,
+    /// The file hasn't changed since the If-Modified-Since date, nothing
+    /// else is known then
+    pub not_modified: bool,
+}
+
+/// Outcome of [`Transport::fetch_range`]
+pub enum RangeResponse {
+    /// The requested bytes, and nothing else
+    Body(Box<dyn Read>),
+    /// The file no longer matches the `if_range` validator
+    Changed,
+    /// The range starts past the end of the file, HTTP status 416
+    NotSatisfiable,
+}
+
+/// Outcome of [`Transport::fetch_ranges`]
+pub enum RangesResponse {
+    /// The parts of a multipart/byteranges response
+    Parts(Multipart),
+    /// The server answered some other way, e.g. with the whole file
+    /// without being asked with If-Range, the ranges are then fetched one
+    /// by one
+    Unsupported,
+    /// The file no longer matches the `if_range` validator
+    Changed,
+}
+
+/// A response with an error status. Transports fail with it as
+/// [`DownloadError::Status`], so the status can decide whether to retry.
+/// The built in HTTP/1.1 transport fails with [`DownloadError::Http`]
+/// holding a `ureq::Error::Status` instead.
+#[derive(Debug)]
+pub struct StatusError {
+    pub url: String,
+    pub status: u16,
+    /// Retry-After header as sent, honored on 429 and 503
+    pub retry_after: Option<String>,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: status code {}", self.url, self.status)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Where the bytes of a download come from. HTTP(S) and FTP(S) are built
+/// in, another transport can be set through
+/// [`DownloaderBuilder::transport`](crate::DownloaderBuilder::transport),
+/// e.g. to serve a file from memory in tests.
+pub trait Transport: Send + Sync {
+    /// Ask about the file at `url` without downloading it
+    fn remote_info(&self, url: &str) -> Result<RemoteInfo, DownloadError>;
+
+    /// Bytes `start..=end` of the file at `url`. With `if_range` set only
+    /// while the file still has that validator, [`RangeResponse::Changed`]
+    /// otherwise.
+    fn fetch_range(&self, url: &str, start: usize, end: usize, if_range: Option<&str>) -> Result<RangeResponse, DownloadError>;
+
+    /// Several ranges in one request, as the parts of a multipart/byteranges
+    /// response. With `if_range` set only while the file still has that
+    /// validator, like [`fetch_range`](Self::fetch_range). The default is
+    /// for transports without multi-range requests and always returns
+    /// [`RangesResponse::Unsupported`].
+    fn fetch_ranges(&self, _url: &str, _ranges: &[(usize, usize)], _if_range: Option<&str>) -> Result<RangesResponse, DownloadError> {
+        Ok(RangesResponse::Unsupported)
+    }
+
+    /// The whole file at `url` in one stream. `accept_encoding` allows a
+    /// gzip or deflate encoded body, decoded by the caller.
+    fn fetch(&self, url: &str, accept_encoding: bool) -> Result<Box<dyn Read>, DownloadError>;
+}
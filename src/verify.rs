@@ -0,0 +1,44 @@
+use std::{fs::File, io::{self, BufReader}, path::Path};
+use sha2::{Digest, Sha256};
+use crate::DownloadError;
+
+// Hex SHA-256 digest of the file at `path`, hashed as it is read
+fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Check a file already on disk against its expected SHA-256 hex digest,
+/// without downloading anything. A different digest fails with
+/// [`DownloadError::ChecksumMismatch`].
+pub fn verify_sha256(path: impl AsRef<Path>, expected: &str) -> Result<(), DownloadError> {
+    let path = path.as_ref();
+    let actual = file_sha256(path)
+        .map_err(|source| DownloadError::Io{context: format!("failed to read {}", path.display()), source})?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(DownloadError::ChecksumMismatch{expected: expected.to_string(), actual});
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use crate::test_server::test_dir;
+
+    #[test]
+    fn digests_are_compared_hex_case_insensitively() {
+        let path = test_dir("verify-sha256").join("abc");
+        fs::write(&path, "abc").unwrap();
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        verify_sha256(&path, digest).unwrap();
+        verify_sha256(&path, &format!(" {}\n", digest.to_uppercase())).unwrap();
+        let err = verify_sha256(&path, &digest.replace('b', "c")).unwrap_err();
+        assert!(matches!(&err, DownloadError::ChecksumMismatch{actual, ..} if actual == digest), "{}", err);
+        let err = verify_sha256(path.with_file_name("missing"), digest).unwrap_err();
+        assert!(matches!(err, DownloadError::Io{..}), "{}", err);
+    }
+}
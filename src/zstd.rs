@@ -0,0 +1,909 @@
+use std::io::{self, BufReader, Read};
+
+// Magic number of zstd frames, little endian
+const MAGIC: u32 = 0xFD2F_B528;
+// Skippable frames have magic numbers 0x184D2A50..=0x184D2A5F
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+// Largest window accepted, the decoded data is kept for up to twice as
+// much. The zstd tool decodes up to 128 MiB and needs `--memory` past
+// that, frames from `--long` or `--ultra` levels can go over.
+const MAX_WINDOW_SIZE: u64 = 8 << 20;
+
+// Baseline and extra bits of each literals length code
+const LITERALS_LENGTHS: [(u32, u32); 36] = [
+    (0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0),
+    (8, 0), (9, 0), (10, 0), (11, 0), (12, 0), (13, 0), (14, 0), (15, 0),
+    (16, 1), (18, 1), (20, 1), (22, 1), (24, 2), (28, 2), (32, 3), (40, 3),
+    (48, 4), (64, 6), (128, 7), (256, 8), (512, 9), (1024, 10), (2048, 11), (4096, 12),
+    (8192, 13), (16384, 14), (32768, 15), (65536, 16),
+];
+// Baseline and extra bits of each match length code
+const MATCH_LENGTHS: [(u32, u32); 53] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0),
+    (11, 0), (12, 0), (13, 0), (14, 0), (15, 0), (16, 0), (17, 0), (18, 0),
+    (19, 0), (20, 0), (21, 0), (22, 0), (23, 0), (24, 0), (25, 0), (26, 0),
+    (27, 0), (28, 0), (29, 0), (30, 0), (31, 0), (32, 0), (33, 0), (34, 0),
+    (35, 1), (37, 1), (39, 1), (41, 1), (43, 2), (47, 2), (51, 3), (59, 3),
+    (67, 4), (83, 4), (99, 5), (131, 7), (259, 8), (515, 9), (1027, 10), (2051, 11),
+    (4099, 12), (8195, 13), (16387, 14), (32771, 15), (65539, 16),
+];
+const MAX_OFFSET_CODE: u8 = 31;
+
+// Distributions of the predefined sequence tables, with their accuracy logs
+const LITERALS_LENGTH_COUNTS: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const MATCH_LENGTH_COUNTS: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1,
+    -1, -1, -1, -1, -1,
+];
+const OFFSET_COUNTS: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+const PREDEFINED_ACCURACY_LOGS: [u32; 3] = [6, 5, 6];
+// Largest accuracy logs of literals length, offset and match length tables
+const MAX_ACCURACY_LOGS: [u32; 3] = [9, 8, 9];
+const MAX_SYMBOLS: [usize; 3] = [35, MAX_OFFSET_CODE as usize, 52];
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid zstd data: {}", message))
+}
+
+// Bits read from the first byte on, lowest bits first, as table
+// descriptions are written
+struct ForwardBits<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl ForwardBits<'_> {
+    // Zeros past the end, bytes_read() tells if that happened
+    fn peek(&self, bits: u32) -> u32 {
+        let mut word = 0u64;
+        for (i, byte) in self.data.iter().skip(self.position / 8).take(8).enumerate() {
+            word |= (*byte as u64) << (8 * i);
+        }
+        ((word >> (self.position % 8)) & ((1 << bits) - 1)) as u32
+    }
+
+    fn read(&mut self, bits: u32) -> u32 {
+        let value = self.peek(bits);
+        self.position += bits as usize;
+        value
+    }
+
+    fn bytes_read(&self) -> usize {
+        self.position.div_ceil(8)
+    }
+}
+
+// Bits read from the last one back to the first, as FSE and Huffman
+// streams are written. The highest set bit of the last byte marks where
+// the stream starts, reading past its first bit yields zeros.
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    // Bits left to read, negative once more were read than there are
+    position: isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> io::Result<Self> {
+        let last = *data.last().ok_or_else(|| invalid("empty bitstream"))?;
+        if last == 0 {
+            return Err(invalid("bitstream without an end mark"));
+        }
+        let padding = last.leading_zeros() as isize + 1;
+        Ok(BackwardBits{data, position: data.len() as isize * 8 - padding})
+    }
+
+    fn read(&mut self, bits: u32) -> u64 {
+        if bits == 0 {
+            return 0;
+        }
+        self.position -= bits as isize;
+        if self.position >= 0 {
+            return self.extract(self.position as usize, bits);
+        }
+        let available = bits as isize + self.position;
+        if available <= 0 {
+            return 0;
+        }
+        self.extract(0, available as u32) << -self.position
+    }
+
+    fn extract(&self, start: usize, bits: u32) -> u64 {
+        let mut word = 0u64;
+        for (i, byte) in self.data[start / 8..].iter().take(8).enumerate() {
+            word |= (*byte as u64) << (8 * i);
+        }
+        (word >> (start % 8)) & ((1 << bits) - 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    // Next state before adding the bits read
+    base: u16,
+}
+
+// Decoding table of a finite state entropy coded stream, its states index
+// the entries
+#[derive(Debug, Clone)]
+struct FseTable {
+    accuracy_log: u32,
+    entries: Vec<FseEntry>,
+}
+
+impl FseTable {
+    // The table a description at the start of `data` stands for, with the
+    // bytes the description took
+    fn read(data: &[u8], max_symbol: usize, max_accuracy_log: u32) -> io::Result<(Self, usize)> {
+        let mut bits = ForwardBits{data, position: 0};
+        let accuracy_log = bits.read(4) + 5;
+        if accuracy_log > max_accuracy_log {
+            return Err(invalid("FSE table accuracy log too large"));
+        }
+        let mut counts = Vec::new();
+        let mut remaining = (1i32 << accuracy_log) + 1;
+        let mut threshold = 1i32 << accuracy_log;
+        let mut width = accuracy_log + 1;
+        while remaining > 1 {
+            if counts.len() > max_symbol {
+                return Err(invalid("FSE table with too many symbols"));
+            }
+            // Small values take a bit less
+            let max = 2 * threshold - 1 - remaining;
+            let peeked = bits.peek(width) as i32;
+            let value = if peeked & (threshold - 1) < max {
+                bits.read(width - 1);
+                peeked & (threshold - 1)
+            } else {
+                bits.read(width);
+                if peeked >= threshold { peeked - max } else { peeked }
+            };
+            let count = value - 1;
+            remaining -= count.abs();
+            counts.push(count as i16);
+            if count == 0 {
+                // How many more symbols have a zero count follows
+                loop {
+                    let repeat = bits.read(2);
+                    counts.extend((0..repeat).map(|_| 0));
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+            while remaining < threshold {
+                width -= 1;
+                threshold >>= 1;
+            }
+        }
+        if remaining != 1 || counts.len() > max_symbol + 1 || bits.bytes_read() > data.len() {
+            return Err(invalid("corrupt FSE table description"));
+        }
+        Ok((FseTable::from_counts(&counts, accuracy_log)?, bits.bytes_read()))
+    }
+
+    fn from_counts(counts: &[i16], accuracy_log: u32) -> io::Result<Self> {
+        let size = 1usize << accuracy_log;
+        let mut entries = vec![FseEntry::default(); size];
+        // Symbols with a count below 1 take the last states, one each
+        let mut high = size;
+        let mut next_state = vec![0usize; counts.len()];
+        for (symbol, &count) in counts.iter().enumerate() {
+            if count == -1 {
+                high = high.checked_sub(1).ok_or_else(|| invalid("corrupt FSE table"))?;
+                entries[high].symbol = symbol as u8;
+                next_state[symbol] = 1;
+            } else {
+                next_state[symbol] = count.max(0) as usize;
+            }
+        }
+        // The others are spread over the rest
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut position = 0;
+        for (symbol, &count) in counts.iter().enumerate() {
+            for _ in 0..count.max(0) {
+                if position >= high {
+                    return Err(invalid("corrupt FSE table"));
+                }
+                entries[position].symbol = symbol as u8;
+                loop {
+                    position = (position + step) & (size - 1);
+                    if position < high {
+                        break;
+                    }
+                }
+            }
+        }
+        if position != 0 {
+            return Err(invalid("corrupt FSE table"));
+        }
+        for entry in entries.iter_mut() {
+            let state = next_state[entry.symbol as usize];
+            next_state[entry.symbol as usize] += 1;
+            let bits = accuracy_log - state.ilog2();
+            entry.bits = bits as u8;
+            entry.base = ((state << bits) - size) as u16;
+        }
+        Ok(FseTable{accuracy_log, entries})
+    }
+
+    // Table of a single symbol, taking no bits
+    fn repeating(symbol: u8) -> Self {
+        FseTable{accuracy_log: 0, entries: vec![FseEntry{symbol, bits: 0, base: 0}]}
+    }
+
+    fn predefined(kind: usize) -> Self {
+        let counts: &[i16] = match kind {
+            0 => &LITERALS_LENGTH_COUNTS,
+            1 => &OFFSET_COUNTS,
+            _ => &MATCH_LENGTH_COUNTS,
+        };
+        FseTable::from_counts(counts, PREDEFINED_ACCURACY_LOGS[kind]).expect("valid predefined table")
+    }
+
+    fn initial_state(&self, bits: &mut BackwardBits) -> usize {
+        bits.read(self.accuracy_log) as usize
+    }
+
+    fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].symbol
+    }
+
+    fn next_state(&self, state: usize, bits: &mut BackwardBits) -> usize {
+        let entry = self.entries[state];
+        entry.base as usize + bits.read(entry.bits as u32) as usize
+    }
+}
+
+// Decoding table of Huffman coded literals, indexed by the next `max_bits`
+// bits of the stream
+#[derive(Debug, Clone)]
+struct HuffmanTable {
+    max_bits: u32,
+    symbols: Vec<u8>,
+    lengths: Vec<u8>,
+}
+
+impl HuffmanTable {
+    // The table a tree description at the start of `data` stands for,
+    // with the bytes the description took
+    fn read(data: &[u8]) -> io::Result<(Self, usize)> {
+        let header = *data.first().ok_or_else(|| invalid("missing Huffman tree description"))? as usize;
+        if header < 128 {
+            let description = data.get(1..1 + header).ok_or_else(|| invalid("truncated Huffman tree description"))?;
+            return Ok((HuffmanTable::from_weights(compressed_weights(description)?)?, 1 + header));
+        }
+        // Weights of 4 bits each, the first in the high bits of a byte
+        let count = header - 127;
+        let packed = data.get(1..1 + count.div_ceil(2)).ok_or_else(|| invalid("truncated Huffman tree description"))?;
+        let weights = (0..count)
+            .map(|i| if i % 2 == 0 { packed[i / 2] >> 4 } else { packed[i / 2] & 0xf })
+            .collect();
+        Ok((HuffmanTable::from_weights(weights)?, 1 + packed.len()))
+    }
+
+    // The weight of the last symbol is implied by the others
+    fn from_weights(mut weights: Vec<u8>) -> io::Result<Self> {
+        if weights.len() > 255 || weights.iter().any(|&weight| weight > 11) {
+            return Err(invalid("corrupt Huffman weights"));
+        }
+        let total: u32 = weights.iter().filter(|&&weight| weight > 0).map(|&weight| 1 << (weight - 1)).sum();
+        if total == 0 {
+            return Err(invalid("corrupt Huffman weights"));
+        }
+        let max_bits = total.ilog2() + 1;
+        let left = (1 << max_bits) - total;
+        if max_bits > 11 || !left.is_power_of_two() {
+            return Err(invalid("corrupt Huffman weights"));
+        }
+        weights.push(left.ilog2() as u8 + 1);
+        let lengths: Vec<u32> = weights.iter()
+            .map(|&weight| if weight > 0 { max_bits + 1 - weight as u32 } else { 0 })
+            .collect();
+        // Longest codes come first in the table
+        let mut rank_counts = [0usize; 12];
+        for &length in &lengths {
+            rank_counts[length as usize] += 1;
+        }
+        let mut rank_starts = [0usize; 12];
+        for bits in (1..=max_bits as usize).rev() {
+            rank_starts[bits - 1] = rank_starts[bits] + (rank_counts[bits] << (max_bits as usize - bits));
+        }
+        let size = 1 << max_bits;
+        let mut table = HuffmanTable{max_bits, symbols: vec![0; size], lengths: vec![0; size]};
+        for (symbol, &length) in lengths.iter().enumerate().filter(|(_, length)| **length > 0) {
+            let start = rank_starts[length as usize];
+            let span = 1 << (max_bits - length);
+            table.symbols[start..start + span].fill(symbol as u8);
+            table.lengths[start..start + span].fill(length as u8);
+            rank_starts[length as usize] += span;
+        }
+        Ok(table)
+    }
+
+    // Append the `count` symbols of a stream, that must end with the last
+    fn decode(&self, stream: &[u8], count: usize, output: &mut Vec<u8>) -> io::Result<()> {
+        let mut bits = BackwardBits::new(stream)?;
+        let mask = (1 << self.max_bits) - 1;
+        let mut state = bits.read(self.max_bits) as usize;
+        for _ in 0..count {
+            output.push(self.symbols[state]);
+            let length = self.lengths[state] as u32;
+            state = ((state << length) | bits.read(length) as usize) & mask;
+        }
+        // The state always holds the next `max_bits` bits
+        if bits.position != -(self.max_bits as isize) {
+            return Err(invalid("Huffman stream size mismatch"));
+        }
+        Ok(())
+    }
+}
+
+// Huffman weights compressed with FSE, decoded by two states in turn
+fn compressed_weights(description: &[u8]) -> io::Result<Vec<u8>> {
+    let (table, size) = FseTable::read(description, 11, 6)?;
+    let mut bits = BackwardBits::new(&description[size..])?;
+    let mut states = [table.initial_state(&mut bits), table.initial_state(&mut bits)];
+    let mut weights = Vec::new();
+    loop {
+        for turn in 0..2 {
+            weights.push(table.symbol(states[turn]));
+            states[turn] = table.next_state(states[turn], &mut bits);
+            // The other state's symbol is the last
+            if bits.position < 0 {
+                weights.push(table.symbol(states[1 - turn]));
+                return Ok(weights);
+            }
+        }
+        if weights.len() > 255 {
+            return Err(invalid("too many Huffman weights"));
+        }
+    }
+}
+
+// What compressed blocks of a frame can refer to from earlier blocks
+struct BlockState {
+    huffman: Option<HuffmanTable>,
+    // Literals length, offset and match length tables
+    sequence_tables: [Option<FseTable>; 3],
+    offsets: [usize; 3],
+    literals: Vec<u8>,
+}
+
+impl BlockState {
+    fn new() -> Self {
+        BlockState{huffman: None, sequence_tables: [None, None, None], offsets: [1, 4, 8], literals: Vec::new()}
+    }
+
+    // Append what a compressed block decodes to to `output`, which holds
+    // the frame's window before it
+    fn decode(&mut self, block: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let size = self.read_literals(block)?;
+        self.execute_sequences(&block[size..], output)
+    }
+
+    // Decode the literals section into `literals` and return its size
+    fn read_literals(&mut self, block: &[u8]) -> io::Result<usize> {
+        let truncated = || invalid("truncated literals section");
+        let header = |size: usize| block.get(..size).ok_or_else(truncated)
+            .map(|bytes| bytes.iter().rev().fold(0usize, |value, &byte| value << 8 | byte as usize));
+        let first = *block.first().ok_or_else(truncated)?;
+        let kind = first & 3;
+        let format = (first >> 2) & 3;
+        self.literals.clear();
+        if kind < 2 {
+            let (size, header_size) = match format {
+                0 | 2 => ((first >> 3) as usize, 1),
+                1 => (header(2)? >> 4, 2),
+                _ => (header(3)? >> 4, 3),
+            };
+            if size > MAX_BLOCK_SIZE {
+                return Err(invalid("literals section too large"));
+            }
+            // Raw or a single byte repeated
+            if kind == 0 {
+                self.literals.extend_from_slice(block.get(header_size..header_size + size).ok_or_else(truncated)?);
+                return Ok(header_size + size);
+            }
+            let byte = *block.get(header_size).ok_or_else(truncated)?;
+            self.literals.resize(size, byte);
+            return Ok(header_size + 1);
+        }
+        let (streams, header_size, width) = match format {
+            0 => (1, 3, 10),
+            1 => (4, 3, 10),
+            2 => (4, 4, 14),
+            _ => (4, 5, 18),
+        };
+        let value = header(header_size)?;
+        let regenerated = (value >> 4) & ((1 << width) - 1);
+        let compressed = (value >> (4 + width)) & ((1 << width) - 1);
+        let mut data = block.get(header_size..header_size + compressed).ok_or_else(truncated)?;
+        // Treeless literals reuse the previous block's table
+        if kind == 2 {
+            let (table, size) = HuffmanTable::read(data)?;
+            self.huffman = Some(table);
+            data = &data[size..];
+        }
+        let table = self.huffman.as_ref().ok_or_else(|| invalid("treeless literals without an earlier Huffman table"))?;
+        if streams == 1 {
+            table.decode(data, regenerated, &mut self.literals)?;
+            return Ok(header_size + compressed);
+        }
+        // A jump table gives the sizes of the first three streams
+        if data.len() < 6 {
+            return Err(truncated());
+        }
+        let sizes = [0, 2, 4].map(|i| u16::from_le_bytes([data[i], data[i + 1]]) as usize);
+        let quarter = regenerated.div_ceil(4);
+        let last = regenerated.checked_sub(3 * quarter).ok_or_else(|| invalid("corrupt literals section"))?;
+        let mut streams = &data[6..];
+        for (i, count) in [quarter, quarter, quarter, last].into_iter().enumerate() {
+            let size = if i < 3 { sizes[i] } else { streams.len() };
+            let stream = streams.get(..size).ok_or_else(truncated)?;
+            table.decode(stream, count, &mut self.literals)?;
+            streams = &streams[size..];
+        }
+        Ok(header_size + compressed)
+    }
+
+    fn execute_sequences(&mut self, data: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let truncated = || invalid("truncated sequences section");
+        let byte = |i: usize| data.get(i).map(|&byte| byte as usize).ok_or_else(truncated);
+        let (count, mut position) = match byte(0)? {
+            count @ 0..=127 => (count, 1),
+            first @ 128..=254 => (((first - 128) << 8) + byte(1)?, 2),
+            _ => (byte(1)? + (byte(2)? << 8) + 0x7f00, 3),
+        };
+        if count == 0 {
+            output.extend_from_slice(&self.literals);
+            return Ok(());
+        }
+        let modes = byte(position)?;
+        position += 1;
+        if modes & 3 != 0 {
+            return Err(invalid("reserved bits set in sequence compression modes"));
+        }
+        // Literals length, offset and match length, in that order
+        for (kind, shift) in [6, 4, 2].into_iter().enumerate() {
+            match (modes >> shift) & 3 {
+                0 => self.sequence_tables[kind] = Some(FseTable::predefined(kind)),
+                1 => {
+                    self.sequence_tables[kind] = Some(FseTable::repeating(byte(position)? as u8));
+                    position += 1;
+                }
+                2 => {
+                    let (table, size) = FseTable::read(&data[position..], MAX_SYMBOLS[kind], MAX_ACCURACY_LOGS[kind])?;
+                    self.sequence_tables[kind] = Some(table);
+                    position += size;
+                }
+                _ => if self.sequence_tables[kind].is_none() {
+                    return Err(invalid("repeated sequence table without an earlier one"));
+                },
+            }
+        }
+        let [Some(literals_lengths), Some(offsets), Some(match_lengths)] = &self.sequence_tables else {
+            unreachable!("all sequence tables are set");
+        };
+        let mut bits = BackwardBits::new(&data[position..])?;
+        let mut literals_state = literals_lengths.initial_state(&mut bits);
+        let mut offset_state = offsets.initial_state(&mut bits);
+        let mut match_state = match_lengths.initial_state(&mut bits);
+        let mut literals = &self.literals[..];
+        for i in 0..count {
+            let offset_code = offsets.symbol(offset_state);
+            let literals_code = literals_lengths.symbol(literals_state) as usize;
+            let match_code = match_lengths.symbol(match_state) as usize;
+            if offset_code > MAX_OFFSET_CODE || literals_code >= LITERALS_LENGTHS.len() || match_code >= MATCH_LENGTHS.len() {
+                return Err(invalid("sequence code out of range"));
+            }
+            let offset_value = (1usize << offset_code) + bits.read(offset_code as u32) as usize;
+            let (base, extra) = MATCH_LENGTHS[match_code];
+            let match_length = base as usize + bits.read(extra) as usize;
+            let (base, extra) = LITERALS_LENGTHS[literals_code];
+            let literals_length = base as usize + bits.read(extra) as usize;
+            if i + 1 < count {
+                literals_state = literals_lengths.next_state(literals_state, &mut bits);
+                match_state = match_lengths.next_state(match_state, &mut bits);
+                offset_state = offsets.next_state(offset_state, &mut bits);
+            }
+            let offset = next_offset(&mut self.offsets, offset_value, literals_length)?;
+            if literals_length > literals.len() {
+                return Err(invalid("sequence uses more literals than were sent"));
+            }
+            output.extend_from_slice(&literals[..literals_length]);
+            literals = &literals[literals_length..];
+            let start = output.len().checked_sub(offset).ok_or_else(|| invalid("match offset past the start of the frame"))?;
+            if offset >= match_length {
+                output.extend_from_within(start..start + match_length);
+            } else {
+                // The match overlaps what it produces
+                for i in start..start + match_length {
+                    output.push(output[i]);
+                }
+            }
+        }
+        if bits.position != 0 {
+            return Err(invalid("sequences bitstream size mismatch"));
+        }
+        output.extend_from_slice(literals);
+        Ok(())
+    }
+}
+
+// The offset a sequence's offset value stands for, values up to 3 repeat
+// one of the last three offsets
+fn next_offset(offsets: &mut [usize; 3], value: usize, literals_length: usize) -> io::Result<usize> {
+    if value > 3 {
+        *offsets = [value - 3, offsets[0], offsets[1]];
+        return Ok(offsets[0]);
+    }
+    let repeat = if literals_length == 0 { value } else { value - 1 };
+    let offset = match repeat {
+        0 => return Ok(offsets[0]),
+        1 => offsets[1],
+        2 => offsets[2],
+        _ => offsets[0].checked_sub(1).filter(|&offset| offset > 0).ok_or_else(|| invalid("zero match offset"))?,
+    };
+    *offsets = match repeat {
+        1 => [offset, offsets[0], offsets[2]],
+        _ => [offset, offsets[0], offsets[1]],
+    };
+    Ok(offset)
+}
+
+const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+// XXH64 with a zero seed, the content checksum of frames
+struct Xxh64 {
+    lanes: [u64; 4],
+    // Bytes short of a whole 32 byte stripe
+    pending: Vec<u8>,
+    length: u64,
+}
+
+fn xxh64_round(lane: u64, input: u64) -> u64 {
+    lane.wrapping_add(input.wrapping_mul(PRIME_2)).rotate_left(31).wrapping_mul(PRIME_1)
+}
+
+fn xxh64_merge(hash: u64, lane: u64) -> u64 {
+    (hash ^ xxh64_round(0, lane)).wrapping_mul(PRIME_1).wrapping_add(PRIME_4)
+}
+
+fn u64_at(data: &[u8], i: usize) -> u64 {
+    u64::from_le_bytes(data[i..i + 8].try_into().unwrap())
+}
+
+impl Xxh64 {
+    fn new() -> Self {
+        let lanes = [PRIME_1.wrapping_add(PRIME_2), PRIME_2, 0, 0u64.wrapping_sub(PRIME_1)];
+        Xxh64{lanes, pending: Vec::with_capacity(32), length: 0}
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.pending.is_empty() {
+            let take = data.len().min(32 - self.pending.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 32 {
+                return;
+            }
+            let stripe = std::mem::take(&mut self.pending);
+            self.stripe(&stripe);
+        }
+        while data.len() >= 32 {
+            self.stripe(&data[..32]);
+            data = &data[32..];
+        }
+        self.pending.extend_from_slice(data);
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (i, lane) in self.lanes.iter_mut().enumerate() {
+            *lane = xxh64_round(*lane, u64_at(stripe, 8 * i));
+        }
+    }
+
+    fn digest(&self) -> u64 {
+        let [a, b, c, d] = self.lanes;
+        let mut hash = if self.length >= 32 {
+            let hash = a.rotate_left(1).wrapping_add(b.rotate_left(7)).wrapping_add(c.rotate_left(12)).wrapping_add(d.rotate_left(18));
+            self.lanes.iter().fold(hash, |hash, &lane| xxh64_merge(hash, lane))
+        } else {
+            PRIME_5
+        };
+        hash = hash.wrapping_add(self.length);
+        let mut rest = &self.pending[..];
+        while rest.len() >= 8 {
+            hash ^= xxh64_round(0, u64_at(rest, 0));
+            hash = hash.rotate_left(27).wrapping_mul(PRIME_1).wrapping_add(PRIME_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            hash ^= (u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64).wrapping_mul(PRIME_1);
+            hash = hash.rotate_left(23).wrapping_mul(PRIME_2).wrapping_add(PRIME_3);
+            rest = &rest[4..];
+        }
+        for &byte in rest {
+            hash ^= (byte as u64).wrapping_mul(PRIME_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME_1);
+        }
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME_3);
+        hash ^= hash >> 32;
+        hash
+    }
+}
+
+// Input ending early is corrupt data rather than an io error
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<()> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("truncated frame"),
+        _ => err,
+    })
+}
+
+struct Frame {
+    window_size: usize,
+    content_size: Option<u64>,
+    decoded: u64,
+    checksum: Option<Xxh64>,
+}
+
+// Streaming decoder of zstd compressed data, frame after frame. Frames
+// needing a dictionary aren't supported.
+pub struct ZstdDecoder<R> {
+    reader: BufReader<R>,
+    // The window of the current frame and what was decoded after it
+    buffer: Vec<u8>,
+    // Bytes of `buffer` already read
+    position: usize,
+    block: Vec<u8>,
+    frame: Option<Frame>,
+    state: BlockState,
+}
+
+impl<R: Read> ZstdDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        ZstdDecoder{
+            reader: BufReader::new(reader),
+            buffer: Vec::new(),
+            position: 0,
+            block: Vec::new(),
+            frame: None,
+            state: BlockState::new(),
+        }
+    }
+
+    fn read_le(&mut self, size: usize) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        read_exact(&mut self.reader, &mut bytes[..size])?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // Start the next frame, false at the end of the input
+    fn read_frame_header(&mut self) -> io::Result<bool> {
+        loop {
+            let mut magic = [0; 4];
+            let mut read = 0;
+            while read < magic.len() {
+                match self.reader.read(&mut magic[read..])? {
+                    0 if read == 0 => return Ok(false),
+                    0 => return Err(invalid("truncated frame")),
+                    n => read += n,
+                }
+            }
+            let magic = u32::from_le_bytes(magic);
+            if magic & !0xf == SKIPPABLE_MAGIC {
+                let size = self.read_le(4)?;
+                if io::copy(&mut self.reader.by_ref().take(size), &mut io::sink())? != size {
+                    return Err(invalid("truncated skippable frame"));
+                }
+                continue;
+            }
+            if magic != MAGIC {
+                return Err(invalid("not a zstd frame"));
+            }
+            break;
+        }
+        let descriptor = self.read_le(1)? as u8;
+        let single_segment = descriptor & 0x20 != 0;
+        if descriptor & 0x08 != 0 {
+            return Err(invalid("reserved frame header bit set"));
+        }
+        let window_size = if single_segment {
+            None
+        } else {
+            let window = self.read_le(1)?;
+            let base = 1u64 << (10 + (window >> 3));
+            Some(base + base / 8 * (window & 7))
+        };
+        let dictionary_id = self.read_le([0, 1, 2, 4][descriptor as usize & 3])?;
+        if dictionary_id != 0 {
+            return Err(invalid("frames with a dictionary are not supported"));
+        }
+        let content_size = match descriptor >> 6 {
+            0 if single_segment => Some(self.read_le(1)?),
+            0 => None,
+            1 => Some(self.read_le(2)? + 256),
+            2 => Some(self.read_le(4)?),
+            _ => Some(self.read_le(8)?),
+        };
+        let window_size = window_size.or(content_size).unwrap_or(0);
+        if window_size > MAX_WINDOW_SIZE {
+            return Err(invalid(&format!("frame window of {} bytes is over the {} byte limit", window_size, MAX_WINDOW_SIZE)));
+        }
+        self.buffer.clear();
+        self.position = 0;
+        self.state = BlockState::new();
+        let checksum = (descriptor & 0x04 != 0).then(Xxh64::new);
+        self.frame = Some(Frame{window_size: window_size as usize, content_size, decoded: 0, checksum});
+        Ok(true)
+    }
+
+    // Decode the next block into the buffer, false at the end of the input
+    fn decode_block(&mut self) -> io::Result<bool> {
+        if self.frame.is_none() && !self.read_frame_header()? {
+            return Ok(false);
+        }
+        let window_size = self.frame.as_ref().map_or(0, |frame| frame.window_size);
+        // Everything was read, only the window needs keeping
+        if self.buffer.len() >= 2 * window_size.max(MAX_BLOCK_SIZE) {
+            self.buffer.drain(..self.buffer.len() - window_size);
+            self.position = self.buffer.len();
+        }
+        let header = self.read_le(3)? as usize;
+        let last = header & 1 != 0;
+        let size = header >> 3;
+        if size > MAX_BLOCK_SIZE {
+            return Err(invalid("block too large"));
+        }
+        let start = self.buffer.len();
+        match (header >> 1) & 3 {
+            0 => {
+                self.buffer.resize(start + size, 0);
+                read_exact(&mut self.reader, &mut self.buffer[start..])?;
+            }
+            1 => {
+                let byte = self.read_le(1)? as u8;
+                self.buffer.resize(start + size, byte);
+            }
+            2 => {
+                self.block.resize(size, 0);
+                read_exact(&mut self.reader, &mut self.block)?;
+                self.state.decode(&self.block, &mut self.buffer)?;
+            }
+            _ => return Err(invalid("reserved block type")),
+        }
+        let frame = self.frame.as_mut().unwrap();
+        frame.decoded += (self.buffer.len() - start) as u64;
+        if let Some(checksum) = &mut frame.checksum {
+            checksum.update(&self.buffer[start..]);
+        }
+        if last {
+            let frame = self.frame.take().unwrap();
+            if frame.content_size.is_some_and(|size| size != frame.decoded) {
+                return Err(invalid("frame content size mismatch"));
+            }
+            if let Some(checksum) = frame.checksum {
+                if self.read_le(4)? as u32 != checksum.digest() as u32 {
+                    return Err(invalid("frame checksum mismatch"));
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for ZstdDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if !self.decode_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What the files in testdata compress: a kilobyte of words repeated
+    // 200 times with one byte changed in each copy, over two blocks
+    fn sample() -> Vec<u8> {
+        let words: [&[u8]; 8] = [b"alpha ", b"beta ", b"gamma ", b"delta\n", b"epsilon ", b"zeta ", b"eta ", b"theta, "];
+        let mut state: u32 = 1;
+        let mut base = Vec::new();
+        while base.len() < 1024 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            base.extend_from_slice(words[(state >> 16) as usize % 8]);
+        }
+        base.truncate(1024);
+        let mut sample = Vec::new();
+        for i in 0..200 {
+            let mut copy = base.clone();
+            copy[i * 37 % 1024] = b'#';
+            sample.extend(copy);
+        }
+        sample
+    }
+
+    fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        ZstdDecoder::new(data).read_to_end(&mut decoded)?;
+        Ok(decoded)
+    }
+
+    // A frame header of a frame without a content size, a dictionary or
+    // a checksum, with the window descriptor `window`
+    fn frame_header(window: u8) -> Vec<u8> {
+        let mut header = MAGIC.to_le_bytes().to_vec();
+        header.extend([0, window]);
+        header
+    }
+
+    #[test]
+    fn decodes_zstd_tool_output() {
+        // `zstd -19 --check --no-content-size`
+        assert_eq!(decode(include_bytes!("../testdata/sample.zst")).unwrap(), sample());
+        // `zstd -1 --no-check`, sized single segment frame
+        assert_eq!(decode(include_bytes!("../testdata/sample-fast.zst")).unwrap(), sample());
+    }
+
+    #[test]
+    fn decodes_frames_after_each_other() {
+        let mut data = include_bytes!("../testdata/sample.zst").to_vec();
+        data.extend((SKIPPABLE_MAGIC + 3).to_le_bytes());
+        data.extend(5u32.to_le_bytes());
+        data.extend(b"skip!");
+        data.extend(include_bytes!("../testdata/sample-fast.zst"));
+        assert_eq!(decode(&data).unwrap(), [sample(), sample()].concat());
+    }
+
+    #[test]
+    fn rejects_broken_frames() {
+        let mut data = include_bytes!("../testdata/sample.zst").to_vec();
+        // The last byte is part of the checksum
+        *data.last_mut().unwrap() ^= 1;
+        let err = decode(&data).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        let data = include_bytes!("../testdata/sample.zst");
+        assert!(decode(&data[..data.len() - 10]).is_err());
+        assert!(decode(b"not zstd at all").is_err());
+    }
+
+    #[test]
+    fn limits_the_window() {
+        // An 8 MiB window with an empty last raw block
+        let mut data = frame_header(13 << 3);
+        data.extend([1, 0, 0]);
+        assert_eq!(decode(&data).unwrap(), b"");
+        // 16 MiB
+        let err = decode(&frame_header(14 << 3)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("frame window of 16777216 bytes"), "{}", err);
+    }
+}
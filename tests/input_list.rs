@@ -0,0 +1,48 @@
+// The binary working through an --input-list of downloads
+use std::{fs, process::Command};
+
+#[path = "../src/test_server.rs"]
+mod test_server;
+
+use test_server::{serve_file, test_data, test_dir, Response, TestServer};
+
+#[test]
+fn downloads_every_entry_and_reports_failures() {
+    let body = test_data(5000);
+    let server_body = body.clone();
+    let server = TestServer::new(move |request| match request.path.as_str() {
+        "/missing.bin" => Response::new(404),
+        _ => serve_file(request, &server_body, None),
+    });
+    let dir = test_dir("input-list");
+    let list = dir.join("list.txt");
+    fs::write(&list, format!(
+        "# first two fine, the third missing\n{}\tfirst.bin\n\n{}\tsecond.bin\n{}\tthird.bin\n{}\tfourth.bin\n",
+        server.url("/a.bin"), server.url("/b.bin"), server.url("/missing.bin"), server.url("/d.bin"),
+    )).unwrap();
+    let run = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_parallel_downloader"))
+        .args(["--input-list", list.to_str().unwrap(), "--output-dir", dir.to_str().unwrap()])
+        .args(args)
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+
+    let output = run(&[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("1 of 4 downloads failed"), "{}", stderr);
+    assert!(stderr.contains(&server.url("/missing.bin")), "{}", stderr);
+    for name in ["first.bin", "second.bin", "fourth.bin"] {
+        assert_eq!(fs::read(dir.join(name)).unwrap(), body, "{}", name);
+    }
+    assert!(!dir.join("third.bin").exists());
+
+    // Stops at the failure
+    for name in ["first.bin", "second.bin", "fourth.bin"] {
+        fs::remove_file(dir.join(name)).unwrap();
+    }
+    let output = run(&["--fail-fast"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(dir.join("second.bin").exists());
+    assert!(!dir.join("fourth.bin").exists());
+}
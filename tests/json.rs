@@ -0,0 +1,60 @@
+// The newline delimited JSON events of --json runs of the binary
+use std::process::Command;
+use serde_json::Value;
+
+#[path = "../src/test_server.rs"]
+mod test_server;
+
+use test_server::{test_data, test_dir, Response, TestServer};
+
+#[test]
+fn events_follow_the_download() {
+    let server = TestServer::serving(test_data(10_000));
+    let dir = test_dir("json-events");
+    let output = Command::new(env!("CARGO_BIN_EXE_parallel_downloader"))
+        .args(["--url", &server.url("/file"), "--file-name", "file", "--json"])
+        .args(["--chunk-size", "1000", "--min-chunk-size", "1", "--workers", "3"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let events: Vec<Value> = String::from_utf8(output.stdout).unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|err| panic!("{}: {}", err, line)))
+        .collect();
+    assert_eq!(events.len(), 12);
+    assert_eq!(events[0]["event"], "start");
+    assert_eq!((events[0]["content_length"].as_u64(), events[0]["total_chunks"].as_u64()), (Some(10_000), Some(10)));
+    let chunks = &events[1..11];
+    let mut ids: Vec<_> = chunks.iter()
+        .map(|event| {
+            assert_eq!(event["event"], "chunk_done");
+            assert_eq!(event["bytes"], 1000);
+            event["id"].as_u64().unwrap()
+        })
+        .collect();
+    ids.sort();
+    assert_eq!(ids, (0..10).collect::<Vec<_>>());
+    for (i, event) in chunks.iter().enumerate() {
+        assert_eq!(event["completed_chunks"].as_u64(), Some(i as u64 + 1));
+        assert_eq!(event["bytes_downloaded"].as_u64(), Some((i as u64 + 1) * 1000));
+    }
+    let done = &events[11];
+    assert_eq!(done["event"], "done");
+    assert_eq!((done["bytes_written"].as_u64(), done["num_chunks"].as_u64()), (Some(10_000), Some(10)));
+    assert_eq!(done["url"], server.url("/file"));
+}
+
+#[test]
+fn errors_stay_on_stderr() {
+    let server = TestServer::new(|_| Response::new(404));
+    let dir = test_dir("json-errors");
+    let output = Command::new(env!("CARGO_BIN_EXE_parallel_downloader"))
+        .args(["--url", &server.url("/file"), "--file-name", "file", "--json"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("download failed"));
+}
@@ -0,0 +1,35 @@
+// Downloads through nothing but the public API, the way a crate depending
+// on this one would
+use std::{fs, time::Duration};
+use parallel_downloader::{DownloadError, Downloader};
+
+#[path = "../src/test_server.rs"]
+mod test_server;
+
+use test_server::{test_data, test_dir, TestServer};
+
+#[test]
+fn downloads_with_the_builder() {
+    let body = test_data(300_000);
+    let server = TestServer::serving(body.clone());
+    let output = test_dir("public-api").join("file.bin");
+    let stats = Downloader::builder()
+        .url(&server.url("/file.bin"))
+        .file_name(&output)
+        .chunk_size(64 * 1024)
+        .workers(4)
+        .retries(2)
+        .timeout(Duration::from_secs(10))
+        .build().unwrap()
+        .run().unwrap();
+    assert_eq!((stats.content_length, stats.num_chunks, stats.bytes_written), (300_000, 5, 300_000));
+    assert_eq!(fs::read(&output).unwrap(), body);
+}
+
+#[test]
+fn invalid_options_fail_to_build() {
+    let err = Downloader::builder().build().err().expect("a url is required");
+    assert!(matches!(err, DownloadError::Other(_)), "{}", err);
+    let err = Downloader::builder().url("http://localhost/file").chunk_size(0).build().err().expect("chunks can't be empty");
+    assert_eq!(err.to_string(), "chunk size must be at least one byte");
+}
@@ -0,0 +1,48 @@
+// --quiet runs of the binary keep stderr clean
+use std::{fs, process::Command};
+
+#[path = "../src/test_server.rs"]
+mod test_server;
+
+use test_server::{serve_file, test_data, test_dir, Response, TestServer};
+
+#[test]
+fn quiet_runs_only_report_errors() {
+    let body = test_data(5000);
+    let server = TestServer::new(move |request| match request.path.as_str() {
+        // Warns about falling back to a single stream
+        "/no-ranges" => Response::new(200).body(body.clone()),
+        "/missing" => Response::new(404),
+        _ => serve_file(request, &body, None),
+    });
+    let dir = test_dir("quiet");
+    let run = |path: &str, args: &[&str]| Command::new(env!("CARGO_BIN_EXE_parallel_downloader"))
+        .args(["--url", &server.url(path), "--force"])
+        .args(args)
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+
+    let output = run("/no-ranges", &["--file-name", "loud", "-v"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(" INFO ") && stderr.contains(" WARN "), "{}", stderr);
+
+    let output = run("/no-ranges", &["--file-name", "quiet", "-q", "--log-path", "quiet.log"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+    // The log file still gets everything
+    let log = fs::read_to_string(dir.join("quiet.log")).unwrap();
+    assert!(log.lines().any(|line| line.starts_with("WARN")), "{}", log);
+
+    let output = run("/missing", &["--file-name", "missing", "-q"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.lines().all(|line| line.contains(" ERROR ")), "{}", stderr);
+    assert!(stderr.contains("download failed"));
+
+    let output = run("/file", &["--file-name", "both", "-q", "-v"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be used with"));
+    assert!(!dir.join("both").exists());
+}
@@ -0,0 +1,68 @@
+// A download killed part way through picks up where it was with --resume
+use std::{
+    fs,
+    process::Command,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[path = "../src/test_server.rs"]
+mod test_server;
+
+use test_server::{serve_file, test_data, test_dir, TestServer};
+
+// Chunks on disk before the kill
+const COMPLETED: usize = 3;
+
+#[test]
+fn killed_downloads_resume_without_fetching_completed_chunks() {
+    let body = test_data(8 * 1024);
+    let holding = Arc::new(AtomicBool::new(true));
+    let server_holding = holding.clone();
+    let server_body = body.clone();
+    // Chunks past the completed ones hang until released
+    let server = TestServer::new(move |request| {
+        if request.range().is_some_and(|(start, _)| start >= COMPLETED * 1024) {
+            while server_holding.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        serve_file(request, &server_body, None)
+    });
+    let dir = test_dir("kill-resume");
+    let command = || {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_parallel_downloader"));
+        command
+            .args(["--url", &server.url("/file"), "--file-name", "file", "--resume"])
+            .args(["--chunk-size", "1024", "--min-chunk-size", "1", "--workers", "1", "-q"])
+            .current_dir(&dir);
+        command
+    };
+
+    let mut child = command().spawn().unwrap();
+    // The progress file ends with the bitmap of completed chunks
+    let started = Instant::now();
+    let completed = || fs::read(dir.join("file.progress")).ok()
+        .and_then(|data| data.last().copied())
+        .map_or(0, |bits| bits.count_ones() as usize);
+    while completed() < COMPLETED {
+        assert!(started.elapsed() < Duration::from_secs(10), "no progress before the kill");
+        thread::sleep(Duration::from_millis(10));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let seen = server.requests().len();
+
+    holding.store(false, Ordering::SeqCst);
+    let status = command().status().unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(dir.join("file")).unwrap(), body);
+    assert!(!dir.join("file.progress").exists());
+    let fetched: Vec<_> = server.requests()[seen..].iter()
+        .filter_map(|request| request.range())
+        .filter(|range| *range != (0, 0))
+        .map(|(start, _)| start / 1024)
+        .collect();
+    assert_eq!(fetched, (COMPLETED..8).collect::<Vec<_>>());
+}
@@ -0,0 +1,25 @@
+// The binary writing a download to stdout, with nothing else mixed in
+use std::process::Command;
+
+#[path = "../src/test_server.rs"]
+mod test_server;
+
+use test_server::{test_data, test_dir, TestServer};
+
+#[test]
+fn downloads_to_stdout_in_order() {
+    let body = test_data(200_000);
+    let server = TestServer::serving(body.clone());
+    // Away from the repo, should anything land in the working directory
+    let dir = test_dir("stdout");
+    let output = Command::new(env!("CARGO_BIN_EXE_parallel_downloader"))
+        .args(["--url", &server.url("/file"), "--file-name", "-"])
+        .args(["--chunk-size", "8KB", "--min-chunk-size", "1", "--workers", "4"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout.len(), body.len());
+    assert!(output.stdout == body, "stdout doesn't match the file");
+    assert!(server.requests().len() > 25);
+}
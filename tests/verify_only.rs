@@ -0,0 +1,36 @@
+// --verify-only checks a file already on disk without downloading it
+use std::{fs, process::Command};
+
+#[path = "../src/test_server.rs"]
+mod test_server;
+
+use test_server::test_dir;
+
+// SHA-256 of "abc"
+const DIGEST: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+#[test]
+fn good_files_pass_and_corrupted_ones_fail() {
+    let dir = test_dir("verify-only");
+    let verify = |name: &str| Command::new(env!("CARGO_BIN_EXE_parallel_downloader"))
+        .args(["--verify-only", "--file-name", name, "--sha256", DIGEST])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    fs::write(dir.join("good"), "abc").unwrap();
+    fs::write(dir.join("corrupted"), "abd").unwrap();
+
+    let output = verify("good");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "good: OK\n");
+
+    let output = verify("corrupted");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("verification of corrupted failed"), "{}", stderr);
+    // Left as it was
+    assert_eq!(fs::read(dir.join("corrupted")).unwrap(), b"abd");
+
+    let output = verify("missing");
+    assert_eq!(output.status.code(), Some(1));
+}